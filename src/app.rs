@@ -1,36 +1,38 @@
-use std::io::Write;
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::{env, fs, io};
+use std::{fs, io};
 
 use anstyle::{Ansi256Color, Color, Style};
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
+use chrono::TimeZone;
 use clap::builder::styling;
 use clap::{CommandFactory, Parser, Subcommand};
 use globset::{Glob, GlobSetBuilder};
 use indexmap::IndexMap;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::ProgressStyle;
+use regex::Regex;
 use rusqlite::Connection;
 
 use crate::cli::clean::CleanArgs;
-use crate::cli::compdb::CompdbCmd;
+use crate::cli::compdb::{CompdbCmd, StoreCmd};
 use crate::cli::mkinfo::MkinfoArgs;
 use crate::cli::perfan::PerfanArgs;
 use crate::cli::review::ReviewArgs;
 use crate::cli::shinit::ShinitArgs;
 use crate::cli::showcc::ShowccArgs;
 use crate::cli::update::UpdateArgs;
-use crate::config::{COMPDB_FILE, COMPDB_STORE, RuaConf};
+use crate::config::{COMPDB_FILE, COMPDB_STORE, CompdbConf, DEFAULT_COMPILER_SHIMS, RuaConf};
 use crate::core::clean;
-use crate::core::compdb::{self, CompdbEngine};
+use crate::core::compdb::{self, CompdbEngine, MergePolicy, SystemIncludes};
 use crate::core::mkinfo::{self, GenBy, MakeOpts};
 use crate::core::perfan;
 use crate::core::review;
 use crate::core::shinit;
 use crate::core::showcc;
 use crate::core::update;
-use crate::utils;
-use crate::utils::progress_bar::{TICK_CHARS, TICK_INTERVAL};
+use crate::utils::progress_bar::{self, TICK_CHARS, TICK_INTERVAL};
+use crate::utils::{self, Runner, status_line};
 
 const STYLE_YELLOW_BOLD: Style = Style::new()
     .fg_color(Some(Color::Ansi256(Ansi256Color(3))))
@@ -60,7 +62,11 @@ pub(crate) enum Comm {
   rua mkinfo A1000      # Makeinfo for A1000 without extra features
   rua mkinfo -6 A1000   # Makeinfo for A1000 with IPv6 enabled
   rua mkinfo -6w 'X\d+' # Makeinfos for X-series products with IPv6 and WebUI enabled using regex pattern
-  rua mkinfo --by-target a-dnv  # Makeinfos for a-dnv target"#, STYLE_YELLOW_BOLD)
+  rua mkinfo --by-target a-dnv  # Makeinfos for a-dnv target
+
+{0}Note:{0:#}
+  The make command is wrapped by `runner` under [mkinfo] or at the top level of the config, which
+  defaults to `hsdocker7 {{logged_command}}`. See `rua compdb gen --help` for details."#, STYLE_YELLOW_BOLD)
     )]
     Mkinfo(MkinfoArgs),
 
@@ -98,6 +104,279 @@ pub(crate) struct Cli {
 
     #[arg(short = 'd', long = "debug", help = "Enable debug option")]
     debug: bool,
+
+    #[arg(
+        short = 'y',
+        long = "yes",
+        visible_alias = "no-input",
+        global = true,
+        help = "Never prompt, assuming yes where a default answer exists"
+    )]
+    yes: bool,
+}
+
+/// Compose the options for generating a compilation database, arguments given on the command
+/// line take precedence over the config.
+fn compose_compdb_options(
+    global_runner: Option<&str>,
+    compdb_conf: Option<&CompdbConf>,
+    defines: &[String],
+    engine: Option<CompdbEngine>,
+    overlay: bool,
+    arguments: bool,
+    merge_policy: Option<MergePolicy>,
+) -> Result<compdb::CompdbOptions> {
+    let final_engine = if let Some(v) = engine {
+        Some(v)
+    } else if let Some(v) = compdb_conf
+        && let Some(x) = v.engine.as_ref()
+    {
+        match x.as_str() {
+            "built-in" => Some(CompdbEngine::BuiltIn),
+            "bear" => Some(CompdbEngine::Bear),
+            "intercept-build" => Some(CompdbEngine::InterceptBuild),
+            "wrapper" => Some(CompdbEngine::Wrapper),
+            y => bail!("Invalid engine specified in config: {}", y),
+        }
+    } else {
+        None
+    };
+
+    // Add defines from config and cli
+    let mut defines_map: IndexMap<String, String> = if let Some(c) = compdb_conf
+        && let Some(x) = c.defines.as_ref()
+    {
+        x.clone()
+    } else {
+        IndexMap::new()
+    };
+    for item in defines.iter() {
+        if let Some((k, v)) = item.split_once("=") {
+            defines_map.insert(k.to_string(), v.to_string());
+        } else {
+            bail!("Invalid key-value pair: {}", item);
+        }
+    }
+
+    let system_includes = if let Some(c) = compdb_conf
+        && let Some(x) = c.system_includes.as_ref()
+    {
+        match x.as_str() {
+            "flags" => Some(SystemIncludes::Flags),
+            "clangd" => Some(SystemIncludes::Clangd),
+            y => bail!("Invalid system includes mode specified in config: {}", y),
+        }
+    } else {
+        None
+    };
+
+    let shims = if let Some(c) = compdb_conf
+        && let Some(list) = c.shims.as_ref()
+    {
+        list.clone()
+    } else {
+        DEFAULT_COMPILER_SHIMS.map(String::from).to_vec()
+    };
+
+    Ok(compdb::CompdbOptions {
+        defines: defines_map,
+        engine: final_engine,
+        bear_path: compdb_conf
+            .and_then(|x| x.bear_path.as_ref())
+            .map(PathBuf::from),
+        intercept_build_path: compdb_conf
+            .and_then(|x| x.intercept_build_path.as_ref())
+            .map(PathBuf::from),
+        shims,
+        overlay: overlay || compdb_conf.and_then(|x| x.overlay).unwrap_or(false),
+        arguments: arguments || compdb_conf.and_then(|x| x.arguments).unwrap_or(false),
+        to_merge: compdb_conf
+            .and_then(|x| x.merge.as_ref())
+            .map(|x| x.iter().map(PathBuf::from).collect())
+            .unwrap_or_default(),
+        merge_policy: merge_policy.unwrap_or(MergePolicy::FirstWins),
+        path_map: conf_path_map(compdb_conf)?,
+        headers: compdb_conf.and_then(|x| x.headers).unwrap_or(false),
+        system_includes,
+        runner: Runner::new(global_runner, compdb_conf.and_then(|x| x.runner.as_deref())),
+        expected_entries: None,
+        keep_log: false,
+    })
+}
+
+/// Path mappings configured under [compdb.path_map]
+fn conf_path_map(compdb_conf: Option<&CompdbConf>) -> Result<Vec<(String, String)>> {
+    compdb::tidy_path_map(
+        compdb_conf
+            .and_then(|x| x.path_map.clone())
+            .unwrap_or_default(),
+    )
+}
+
+/// Flag rules configured under [compdb.flags]
+fn conf_flag_rules(compdb_conf: Option<&CompdbConf>) -> Result<compdb::FlagRules> {
+    let Some(c) = compdb_conf.and_then(|x| x.flags.as_ref()) else {
+        return Ok(compdb::FlagRules::default());
+    };
+    compdb::FlagRules::new(
+        c.remove.as_deref().unwrap_or_default(),
+        &c.replace.clone().unwrap_or_default(),
+        c.append.as_deref().unwrap_or_default(),
+    )
+    .context("Invalid rules under [compdb.flags]")
+}
+
+/// Apply the flag rules to the compilation database in use, after the original is archived
+fn filter_flags(compdb_conf: Option<&CompdbConf>) -> Result<()> {
+    let rules = conf_flag_rules(compdb_conf)?;
+    if rules.is_empty() {
+        return Ok(());
+    }
+    status_line::begin("Applying the flag rules...")?;
+    let changed = compdb::filter_compdb_flags(&rules)?;
+    status_line::end(format!(
+        "Applying the flag rules...ok ({} entries changed)",
+        changed
+    ));
+    Ok(())
+}
+
+/// Look up the make directory and target of the product by mkinfo, letting the user pick one
+/// when the name matches several
+fn select_compile_info(
+    repo_info: &utils::RepoInfo,
+    conf: &RuaConf,
+    name: &str,
+    ipv6: bool,
+    no_input: bool,
+) -> Result<(String, String)> {
+    let mut makeflag = mkinfo::MakeFlag::RELEASE;
+    if ipv6 {
+        makeflag |= mkinfo::MakeFlag::IPV6;
+    }
+    let makeopts = MakeOpts {
+        flag: makeflag,
+        image_server: None,
+        nostrip_bins: Vec::new(),
+        defines: IndexMap::new(),
+        runner: Runner::new(
+            conf.runner.as_deref(),
+            conf.mkinfo.as_ref().and_then(|x| x.runner.as_deref()),
+        ),
+    };
+    let compile_infos = mkinfo::gen_mkinfo(GenBy::Nickname(name.to_string()), makeopts, repo_info)?;
+
+    // Products on the same platform share the make directory and target
+    let mut candidates: IndexMap<(String, String), Vec<String>> = IndexMap::new();
+    for item in compile_infos.into_iter() {
+        candidates
+            .entry((item.make_directory, item.make_target))
+            .or_default()
+            .push(item.product_name);
+    }
+    if candidates.is_empty() {
+        bail!("No product matches {}", name);
+    }
+    if candidates.len() == 1 {
+        return Ok(candidates.into_keys().next().unwrap());
+    }
+
+    eprintln!("Products matching {} are built by different targets:", name);
+    for (i, ((directory, target), products)) in candidates.iter().enumerate() {
+        eprintln!(
+            "  {:>2}) {} {} ({})",
+            i + 1,
+            directory,
+            target,
+            products.join(", ")
+        );
+    }
+    if no_input || !io::stdin().is_terminal() {
+        bail!(
+            "Not selecting one in non-interactive mode, give the make directory and target instead"
+        );
+    }
+    eprint!("Select one [1-{}]: ", candidates.len());
+    io::stderr().flush()?;
+    let mut input_buf = String::new();
+    io::stdin().read_line(&mut input_buf)?;
+    let index = input_buf
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|x| (1..=candidates.len()).contains(x))
+        .context(format!("Invalid selection: {}", input_buf.trim()))?;
+    Ok(candidates.swap_remove_index(index - 1).unwrap().0)
+}
+
+/// Archive the compilation database in the current directory as a new generation for the
+/// target and put it in use, returning the generation
+fn archive_generated(
+    conn: &mut Connection,
+    repo_info: &utils::RepoInfo,
+    target: &str,
+    remark: Option<&str>,
+) -> Result<i64> {
+    let pb = progress_bar::no_length().with_style(ProgressStyle::with_template(&format!(
+        "Archiving the newly generated compilation database for {}...",
+        target
+    ))?);
+    pb.tick();
+    let generation = compdb::archive_compdb(
+        conn,
+        repo_info.branch(),
+        repo_info.commit_id(),
+        target,
+        COMPDB_FILE,
+        remark,
+        true,
+    )?;
+    pb.set_style(ProgressStyle::with_template(&format!(
+        "Archived the newly generated compilation database for {}.",
+        target
+    ))?);
+    pb.finish_with_message("ok");
+    Ok(generation)
+}
+
+/// Apply the retention policy, reporting the generations removed
+fn collect_garbage(
+    conn: &mut Connection,
+    compdb_conf: Option<&CompdbConf>,
+    dry_run: bool,
+) -> Result<()> {
+    let Some(retention) = compdb_conf.and_then(|x| x.retention.as_ref()) else {
+        eprintln!("No retention policy configured under [compdb.retention]");
+        return Ok(());
+    };
+    let policy = compdb::RetentionPolicy {
+        keep_per_target: retention.keep_per_target,
+        max_age_days: retention.max_age_days,
+        max_size: retention.max_size_mb.map(|x| x * 1024 * 1024),
+    };
+    let (collected, size_before, size_after) = compdb::gc_generations(conn, &policy, dry_run)?;
+    for item in collected.iter() {
+        eprintln!(
+            "{} generation {} ({} on {}, {}): {}",
+            if dry_run { "Would remove" } else { "Removed" },
+            item.generation,
+            item.target,
+            item.branch,
+            chrono::Local
+                .timestamp_opt(item.timestamp, 0)
+                .unwrap()
+                .format("%Y-%m-%dT%H:%M:%S"),
+            item.reason
+        );
+    }
+    eprintln!(
+        "{} generations {}, store {} -> {}",
+        collected.len(),
+        if dry_run { "to remove" } else { "removed" },
+        utils::human_bytes(size_before),
+        utils::human_bytes(size_after)
+    );
+    Ok(())
 }
 
 pub(crate) fn run_app(args: &Cli) -> Result<()> {
@@ -138,143 +417,381 @@ pub(crate) fn run_app(args: &Cli) -> Result<()> {
             let repo_info = utils::RepoInfo::new()?;
             let conf = RuaConf::new(&repo_info)?;
             let rua_cache = Path::new(COMPDB_STORE);
+            // The hook stays quiet unless opted in
+            if let CompdbCmd::Use {
+                from_hook: true, ..
+            } = compdb_comm
+                && (!rua_cache.is_file()
+                    || !conf
+                        .compdb
+                        .as_ref()
+                        .and_then(|x| x.auto_use)
+                        .unwrap_or(false))
+            {
+                return Ok(());
+            }
+            let no_input = args.yes || conf.no_input.unwrap_or(false);
             if !rua_cache.is_file() {
-                print!("The compilation database store does not exist, create it? [Y/n]: ");
-                io::stdout().flush()?;
-                let mut input_buf = String::new();
-                io::stdin().read_line(&mut input_buf)?;
-                let input = input_buf.trim();
-                match input.trim().to_lowercase().as_str() {
-                    "y" | "yes" | "" => {
-                        fs::create_dir_all(".rua")?;
+                if no_input {
+                    fs::create_dir_all(".rua")?;
+                } else if !io::stdin().is_terminal() {
+                    bail!(
+                        "The compilation database store does not exist and stdin is not a terminal to confirm creating it, rerun with --yes"
+                    );
+                } else {
+                    print!("The compilation database store does not exist, create it? [Y/n]: ");
+                    io::stdout().flush()?;
+                    let mut input_buf = String::new();
+                    io::stdin().read_line(&mut input_buf)?;
+                    let input = input_buf.trim();
+                    match input.trim().to_lowercase().as_str() {
+                        "y" | "yes" | "" => {
+                            fs::create_dir_all(".rua")?;
+                        }
+                        _ => return Ok(()),
                     }
-                    _ => return Ok(()),
                 }
             }
 
-            let conn = Connection::open(COMPDB_STORE)?;
-            compdb::create_tables(&conn)?;
+            let mut conn = compdb::open_store(COMPDB_STORE)?;
+            compdb::migrate_store(&mut conn, Path::new(COMPDB_STORE))?;
+
+            let conf_merge_policy = conf.compdb.as_ref().and_then(|x| x.merge_policy);
 
             match compdb_comm {
                 CompdbCmd::Gen {
                     product_dir,
-                    make_target,
+                    product,
+                    ipv6,
+                    make_targets,
                     defines,
                     engine,
                     bear_path,
                     intercept_build_path,
+                    overlay,
+                    arguments,
+                    headers,
+                    system_includes,
+                    keep_log,
                     merge_seq: to_merge,
+                    merge_policy,
+                    union,
                 } => {
-                    let compdb_conf = conf.compdb;
-
-                    // Get bear path from config or argument
-                    let mut final_bear_path = None;
-                    if let Some(v) = bear_path.as_ref() {
-                        final_bear_path = Some(Path::new(v));
-                    } else if let Some(v) = compdb_conf.as_ref()
-                        && let Some(x) = v.bear_path.as_ref()
-                    {
-                        final_bear_path = Some(Path::new(x))
-                    }
-
-                    // Get intercept-build path from config or argument
-                    let final_intercept_build_path = if let Some(v) = intercept_build_path.as_ref()
-                    {
-                        Some(Path::new(v))
-                    } else if let Some(v) = compdb_conf.as_ref()
-                        && let Some(x) = v.intercept_build_path.as_ref()
-                    {
-                        Some(Path::new(x))
-                    } else {
-                        None
-                    };
-
-                    let final_engine = if let Some(v) = engine {
-                        Some(v)
-                    } else if let Some(v) = compdb_conf.as_ref()
-                        && let Some(x) = v.engine.as_ref()
-                    {
-                        match x.as_str() {
-                            "built-in" => Some(CompdbEngine::BuiltIn),
-                            "bear" => Some(CompdbEngine::Bear),
-                            "intercept-build" => Some(CompdbEngine::InterceptBuild),
-                            y => bail!("Invalid engine specified in config: {}", y),
-                        }
-                    } else {
-                        None
-                    };
-
-                    // Add defines from config and cli
-                    let mut defines_map: IndexMap<String, String> = if let Some(c) =
-                        compdb_conf.as_ref()
-                        && let Some(x) = c.defines.as_ref()
+                    let (product_dir, make_targets) = if let Some(name) = product {
+                        let (directory, target) =
+                            select_compile_info(&repo_info, &conf, &name, ipv6, no_input)?;
+                        (directory, vec![target])
+                    } else if !make_targets.is_empty() {
+                        (product_dir.unwrap(), make_targets)
+                    } else if let Some(c) = conf.compdb.as_ref()
+                        && let Some(x) = c.targets.as_ref()
+                        && !x.is_empty()
                     {
-                        x.clone()
+                        (product_dir.unwrap(), x.clone())
                     } else {
-                        IndexMap::new()
+                        bail!(
+                            "No target specified, on the command line or by `targets` under [compdb]"
+                        );
                     };
-                    for item in defines.iter() {
-                        if let Some((k, v)) = item.split_once("=") {
-                            defines_map.insert(k.to_string(), v.to_string());
-                        } else {
-                            bail!("Invalid key-value pair: {}", item);
-                        }
+                    let make_targets: Vec<String> = make_targets
+                        .into_iter()
+                        .map(|x| {
+                            if ipv6 && !x.ends_with("-ipv6") {
+                                x + "-ipv6"
+                            } else {
+                                x
+                            }
+                        })
+                        .collect();
+                    let mut compdb_options = compose_compdb_options(
+                        conf.runner.as_deref(),
+                        conf.compdb.as_ref(),
+                        &defines,
+                        engine,
+                        overlay,
+                        arguments,
+                        merge_policy.or(conf_merge_policy),
+                    )?;
+                    compdb_options.headers |= headers;
+                    compdb_options.keep_log = keep_log;
+                    if system_includes.is_some() {
+                        compdb_options.system_includes = system_includes;
+                    }
+                    if let Some(v) = bear_path {
+                        compdb_options.bear_path = Some(PathBuf::from(v));
+                    }
+                    if let Some(v) = intercept_build_path {
+                        compdb_options.intercept_build_path = Some(PathBuf::from(v));
                     }
-
-                    let mut merge_list = if let Some(c) = compdb_conf.as_ref()
-                        && let Some(list) = c.merge.as_ref()
-                    {
-                        list.iter().map(PathBuf::from).collect()
-                    } else {
-                        Vec::new()
-                    };
                     if let Some(list) = to_merge {
                         for item in list.iter().map(PathBuf::from) {
-                            merge_list.push(item);
+                            compdb_options.to_merge.push(item);
                         }
                     }
+                    // The engines all build in the same tree and write compile_commands.json
+                    // there, so the targets are generated one after another. The output lock is
+                    // let go while building only, so that other runs may build meanwhile.
+                    let mut generations = Vec::with_capacity(make_targets.len());
+                    let mut output_lock = None;
+                    for (i, make_target) in make_targets.iter().enumerate() {
+                        drop(output_lock.take());
+                        if make_targets.len() > 1 {
+                            eprintln!(
+                                "Generating compilation database for {} ({}/{})",
+                                make_target,
+                                i + 1,
+                                make_targets.len()
+                            );
+                        }
+                        let mut options = compdb_options.clone();
+                        // The previous generation for the target tells how far the build has got
+                        options.expected_entries = compdb::get_last_entries(&conn, make_target)?;
+                        output_lock = Some(compdb::gen_compdb(
+                            &repo_info,
+                            &product_dir,
+                            make_target,
+                            options,
+                        )?);
+                        generations.push(archive_generated(
+                            &mut conn,
+                            &repo_info,
+                            make_target,
+                            None,
+                        )?);
+                    }
 
-                    let compdb_options = compdb::CompdbOptions {
-                        defines: defines_map,
-                        engine: final_engine,
-                        bear_path: final_bear_path.map(|x| x.to_path_buf()),
-                        intercept_build_path: final_intercept_build_path.map(|x| x.to_path_buf()),
-                        to_merge: merge_list,
-                    };
-                    compdb::gen_compdb(&repo_info, &product_dir, &make_target, compdb_options)?;
+                    if union && generations.len() > 1 {
+                        status_line::begin("Uniting the compilation databases...")?;
+                        let summary = compdb::union_generations(
+                            &conn,
+                            &generations,
+                            compdb_options.merge_policy,
+                        )?;
+                        status_line::end(format!(
+                            "Uniting the compilation databases...ok ({})",
+                            summary
+                        ));
+                        let remark = format!(
+                            "union of generations {}",
+                            generations
+                                .iter()
+                                .map(|x| x.to_string())
+                                .collect::<Vec<String>>()
+                                .join(", ")
+                        );
+                        archive_generated(
+                            &mut conn,
+                            &repo_info,
+                            &make_targets.join("+"),
+                            Some(&remark),
+                        )?;
+                    }
+                    filter_flags(conf.compdb.as_ref())?;
+                    if conf
+                        .compdb
+                        .as_ref()
+                        .and_then(|x| x.retention.as_ref())
+                        .and_then(|x| x.auto_gc)
+                        .unwrap_or(false)
+                    {
+                        collect_garbage(&mut conn, conf.compdb.as_ref(), false)?;
+                    }
+                    Ok(())
+                }
+                CompdbCmd::Update {
+                    defines,
+                    engine,
+                    bear_path,
+                    intercept_build_path,
+                    overlay,
+                    arguments,
+                    keep_log,
+                    subdir,
+                    make_target,
+                } => {
+                    let parent = compdb::get_current_generation(&conn)?
+                        .context("No generation in use to update, generate one first")?;
+                    let mut compdb_options = compose_compdb_options(
+                        conf.runner.as_deref(),
+                        conf.compdb.as_ref(),
+                        &defines,
+                        engine,
+                        overlay,
+                        arguments,
+                        conf_merge_policy,
+                    )?;
+                    compdb_options.to_merge.clear();
+                    compdb_options.keep_log = keep_log;
+                    if let Some(v) = bear_path {
+                        compdb_options.bear_path = Some(PathBuf::from(v));
+                    }
+                    if let Some(v) = intercept_build_path {
+                        compdb_options.intercept_build_path = Some(PathBuf::from(v));
+                    }
+                    // Splice into the original commands, not the ones rewritten by the flag rules
+                    if !conf_flag_rules(conf.compdb.as_ref())?.is_empty() {
+                        compdb::checkout_unfiltered(&conn, &compdb_options.path_map)?;
+                    }
+                    let (removed, added, _output_lock) = compdb::update_compdb(
+                        &repo_info,
+                        Path::new(&subdir),
+                        &make_target,
+                        compdb_options,
+                    )?;
+                    eprintln!(
+                        "Spliced entries under {}...ok ({} removed, {} added)",
+                        subdir, removed, added
+                    );
 
-                    // Archive the newly generated compilation database
-                    let pb = ProgressBar::no_length().with_style(ProgressStyle::with_template(
-                        "Archiving the newly generated compilation database...",
-                    )?);
-                    pb.tick();
-                    let rows = compdb::archive_compdb(
-                        &conn,
+                    status_line::begin("Archiving the updated compilation database...")?;
+                    compdb::archive_compdb(
+                        &mut conn,
                         repo_info.branch(),
                         repo_info.commit_id(),
                         make_target.as_str(),
-                        "compile_commands.json",
+                        COMPDB_FILE,
+                        Some(&format!("derived from generation {}", parent)),
+                        true,
                     )?;
-                    if rows == 0 {
-                        eprintln!();
-                        bail!(
-                            "\rFailed to archive the newly generated compilation database to store"
-                        );
-                    }
-                    pb.set_style(ProgressStyle::with_template(
-                        "Archived the newly generated compilation database.",
-                    )?);
-                    pb.finish_with_message("ok");
-
-                    // Get the generation id and insert it into the history table
-                    if let Some(generation) = compdb::get_biggest_generation(&conn)? {
-                        compdb::set_current_generation(&conn, generation)?;
+                    status_line::end("Archiving the updated compilation database...ok");
+                    filter_flags(conf.compdb.as_ref())?;
+                    Ok(())
+                }
+                CompdbCmd::Diff { format, from, to } => {
+                    let diff = compdb::diff_generations(&conn, from, to)?;
+                    compdb::print_diff(&diff, format)
+                }
+                CompdbCmd::Check { generation, file } => {
+                    let records = match generation {
+                        Some(v) => compdb::read_generation(&conn, v)?,
+                        None => compdb::read_compdb(Path::new(&file))?,
+                    };
+                    let runner = Runner::new(
+                        conf.runner.as_deref(),
+                        conf.compdb.as_ref().and_then(|x| x.runner.as_deref()),
+                    );
+                    let findings =
+                        compdb::check_compdb(&records, &runner, Path::new(repo_info.work_dir()));
+                    let errors = compdb::print_findings(&findings, records.len());
+                    if errors > 0 {
+                        bail!("Found {} errors in the compilation database", errors);
                     }
                     Ok(())
                 }
-                CompdbCmd::Ls => compdb::list_generations(&conn),
-                CompdbCmd::Use { generation } => {
-                    compdb::use_generation(&conn, generation)?;
+                CompdbCmd::Export {
+                    generations,
+                    output,
+                } => {
+                    status_line::begin(format!("Exporting generations to {}...", output))?;
+                    let n = compdb::export_generations(
+                        &conn,
+                        repo_info.work_dir(),
+                        &generations,
+                        &output,
+                    )?;
+                    status_line::end(format!(
+                        "Exporting generations to {}...ok ({} exported)",
+                        output, n
+                    ));
+                    Ok(())
+                }
+                CompdbCmd::Import { rebase, bundle } => {
+                    status_line::begin(format!("Importing generations from {}...", bundle))?;
+                    let imported = compdb::import_generations(
+                        &mut conn,
+                        repo_info.work_dir(),
+                        &bundle,
+                        rebase,
+                    )?;
+                    status_line::end(format!(
+                        "Importing generations from {}...ok (imported as {})",
+                        bundle,
+                        imported
+                            .iter()
+                            .map(|x| x.to_string())
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    ));
+                    Ok(())
+                }
+                CompdbCmd::Store { store_comm } => match store_comm {
+                    StoreCmd::Info => compdb::show_store_info(&conn, Path::new(COMPDB_STORE)),
+                },
+                CompdbCmd::Gc { dry_run } => {
+                    collect_garbage(&mut conn, conf.compdb.as_ref(), dry_run)
+                }
+                CompdbCmd::Ls {
+                    branch,
+                    target,
+                    since,
+                    remark,
+                    sort,
+                    reverse,
+                    format,
+                } => {
+                    let options = compdb::ListOptions {
+                        branch,
+                        target,
+                        since: since.as_deref().map(compdb::parse_since).transpose()?,
+                        remark: remark
+                            .as_deref()
+                            .map(Regex::new)
+                            .transpose()
+                            .context("Invalid regex for --remark")?,
+                        sort,
+                        reverse,
+                        format,
+                    };
+                    compdb::list_generations(&conn, &options)
+                }
+                CompdbCmd::Recover => compdb::recover_makefiles(&repo_info),
+                CompdbCmd::Use {
+                    generation,
+                    auto,
+                    target,
+                    from_hook,
+                } => {
+                    let generation = match generation {
+                        Some(v) => v,
+                        None if auto => {
+                            match compdb::find_generation_auto(&conn, &repo_info, target.as_deref())
+                            {
+                                Ok(v) => v,
+                                // Never fail the checkout for it
+                                Err(e) if from_hook => {
+                                    eprintln!("rua: {}", e);
+                                    return Ok(());
+                                }
+                                Err(e) => return Err(e),
+                            }
+                        }
+                        None => unreachable!(),
+                    };
+                    compdb::use_generation(
+                        &conn,
+                        generation,
+                        &conf_path_map(conf.compdb.as_ref())?,
+                        &conf_flag_rules(conf.compdb.as_ref())?,
+                    )?;
+                    Ok(())
+                }
+                CompdbCmd::Hook { uninstall } => {
+                    compdb::install_hook(&repo_info, uninstall)?;
+                    if uninstall {
+                        eprintln!("Uninstalled the post-checkout hook");
+                    } else {
+                        eprintln!("Installed the post-checkout hook");
+                        if !conf
+                            .compdb
+                            .as_ref()
+                            .and_then(|x| x.auto_use)
+                            .unwrap_or(false)
+                        {
+                            eprintln!(
+                                "Set `auto_use = true` under [compdb] in .rua/config.toml to enable it"
+                            );
+                        }
+                    }
                     Ok(())
                 }
                 CompdbCmd::Del {
@@ -283,7 +800,6 @@ pub(crate) fn run_app(args: &Cli) -> Result<()> {
                     new,
                     all,
                 } => {
-                    let mut stderr_ = io::stderr();
                     if let Some(generations) = some {
                         let generations_string = generations
                             .iter()
@@ -291,49 +807,45 @@ pub(crate) fn run_app(args: &Cli) -> Result<()> {
                             .collect::<Vec<String>>()
                             .join(" ");
                         let many = generations.len() > 1;
-                        eprint!(
+                        status_line::begin(format!(
                             "Removing generation{} {}...",
                             if many { "s" } else { "" },
                             generations_string
-                        );
-                        stderr_.flush()?;
+                        ))?;
                         compdb::remove_generation(&conn, compdb::DelOpt::Generations(generations))?;
-                        eprintln!(
-                            "\rRemoving generation{} {}...ok",
+                        status_line::end(format!(
+                            "Removing generation{} {}...ok",
                             if many { "s" } else { "" },
                             generations_string
-                        );
+                        ));
                     } else if let Some(n) = old {
-                        eprint!(
+                        status_line::begin(format!(
                             "Removing {} oldest generation{}...",
                             n,
                             if n > 1 { "s" } else { "" }
-                        );
-                        stderr_.flush()?;
+                        ))?;
                         compdb::remove_generation(&conn, compdb::DelOpt::Oldest(n))?;
-                        eprintln!(
-                            "\rRemoving {} oldest generation{}...ok",
+                        status_line::end(format!(
+                            "Removing {} oldest generation{}...ok",
                             n,
                             if n > 1 { "s" } else { "" }
-                        );
+                        ));
                     } else if let Some(n) = new {
-                        eprint!(
+                        status_line::begin(format!(
                             "Removing {} newest generation{}...",
                             n,
                             if n > 1 { "s" } else { "" }
-                        );
-                        stderr_.flush()?;
+                        ))?;
                         compdb::remove_generation(&conn, compdb::DelOpt::Newest(n))?;
-                        eprintln!(
-                            "\rRemoving {} newest generation{}...ok",
+                        status_line::end(format!(
+                            "Removing {} newest generation{}...ok",
                             n,
                             if n > 1 { "s" } else { "" }
-                        );
+                        ));
                     } else if all {
-                        eprint!("Removing all generations...");
-                        stderr_.flush()?;
+                        status_line::begin("Removing all generations...")?;
                         compdb::remove_generation(&conn, compdb::DelOpt::All)?;
-                        eprintln!("\rRemoving all generations...ok");
+                        status_line::end("Removing all generations...ok");
                     };
                     Ok(())
                 }
@@ -345,43 +857,80 @@ pub(crate) fn run_app(args: &Cli) -> Result<()> {
                     let compdb_path = compdb_path
                         .as_ref()
                         .map_or_else(|| COMPDB_FILE, |x| x.as_str());
-                    eprint!("Archiving compilation database for {}...", target);
-                    io::stderr().flush()?;
+                    status_line::begin(format!(
+                        "Archiving compilation database for {}...",
+                        target
+                    ))?;
                     let commit_id = commit_id
                         .as_deref()
                         .unwrap_or_else(|| repo_info.commit_id());
+                    // Only the compilation database in the current directory is the one in use
+                    let in_use = fs::canonicalize(compdb_path)
+                        .is_ok_and(|x| fs::canonicalize(COMPDB_FILE).is_ok_and(|y| x == y));
+                    // Archive the original commands, not the ones rewritten by the flag rules
+                    if in_use && !conf_flag_rules(conf.compdb.as_ref())?.is_empty() {
+                        compdb::checkout_unfiltered(&conn, &conf_path_map(conf.compdb.as_ref())?)?;
+                    }
                     compdb::archive_compdb(
-                        &conn,
+                        &mut conn,
                         repo_info.branch(),
                         commit_id,
                         target.as_str(),
                         compdb_path,
+                        None,
+                        in_use,
                     )?;
-                    eprintln!("\rArchiving compilation database for {}...ok", target);
-                    let file = Path::new(compdb_path);
-                    let file_name = file.file_name();
-                    let parent_dir = file.parent().unwrap();
-                    let current_dir = env::current_dir().unwrap();
-                    if file_name.is_some_and(|x| x == "compile_commands.json")
-                        && parent_dir == current_dir
+                    status_line::end(format!(
+                        "Archiving compilation database for {}...ok",
+                        target
+                    ));
+                    if in_use {
+                        filter_flags(conf.compdb.as_ref())?;
+                    }
+                    if conf
+                        .compdb
+                        .as_ref()
+                        .and_then(|x| x.retention.as_ref())
+                        .and_then(|x| x.auto_gc)
+                        .unwrap_or(false)
                     {
-                        let generation = compdb::get_biggest_generation(&conn)?.unwrap();
-                        compdb::set_current_generation(&conn, generation)?;
+                        collect_garbage(&mut conn, conf.compdb.as_ref(), false)?;
                     }
                     Ok(())
                 }
                 CompdbCmd::Merge {
                     target,
                     commit: commit_id,
+                    arguments,
+                    merge_policy,
                     files,
                 } => {
-                    let pbar = ProgressBar::no_length().with_style(
+                    let pbar = progress_bar::no_length().with_style(
                         ProgressStyle::with_template("Merging compilation databases...{msg}")?
                             .tick_chars(TICK_CHARS),
                     );
                     pbar.enable_steady_tick(TICK_INTERVAL);
-                    compdb::merge_compdb(files)?;
-                    pbar.finish_with_message("ok");
+                    // Merge into the original commands, not the ones rewritten by the flag rules
+                    if !conf_flag_rules(conf.compdb.as_ref())?.is_empty() {
+                        compdb::checkout_unfiltered(&conn, &conf_path_map(conf.compdb.as_ref())?)?;
+                    }
+                    let summary = compdb::merge_compdb(
+                        files,
+                        merge_policy
+                            .or(conf_merge_policy)
+                            .unwrap_or(MergePolicy::FirstWins),
+                    )?;
+                    compdb::remap_compdb(&conf_path_map(conf.compdb.as_ref())?)?;
+                    if arguments
+                        || conf
+                            .compdb
+                            .as_ref()
+                            .and_then(|x| x.arguments)
+                            .unwrap_or(false)
+                    {
+                        compdb::tokenize_compdb()?;
+                    }
+                    pbar.finish_with_message(format!("ok ({})", summary));
                     let revision = commit_id
                         .as_deref()
                         .unwrap_or_else(|| repo_info.commit_id());
@@ -390,37 +939,79 @@ pub(crate) fn run_app(args: &Cli) -> Result<()> {
                     )?);
                     pbar.enable_steady_tick(TICK_INTERVAL);
                     compdb::archive_compdb(
-                        &conn,
+                        &mut conn,
                         repo_info.branch(),
                         revision,
                         target.as_str(),
                         COMPDB_FILE,
-                    )?;
-                    compdb::set_current_generation(
-                        &conn,
-                        compdb::get_biggest_generation(&conn)?.unwrap(),
+                        None,
+                        true,
                     )?;
                     pbar.finish_with_message("ok");
+                    filter_flags(conf.compdb.as_ref())?;
+                    Ok(())
+                }
+                CompdbCmd::Rewrite {
+                    maps,
+                    generations,
+                    all,
+                } => {
+                    let path_map = if maps.is_empty() {
+                        conf_path_map(conf.compdb.as_ref())?
+                    } else {
+                        let mut pairs = Vec::new();
+                        for item in maps.iter() {
+                            match item.split_once("=") {
+                                Some((k, v)) => pairs.push((k.to_string(), v.to_string())),
+                                None => bail!("Invalid path mapping: {}, expecting FROM=TO", item),
+                            }
+                        }
+                        compdb::tidy_path_map(pairs)?
+                    };
+                    if path_map.is_empty() {
+                        bail!(
+                            "No path mapping given by --map or configured under [compdb.path_map]"
+                        );
+                    }
+                    let generations = if all {
+                        compdb::get_all_generations(&conn)?
+                    } else {
+                        generations
+                    };
+                    let rewritten =
+                        compdb::rewrite_generations(&mut conn, &generations, &path_map)?;
+                    for (generation, changed) in rewritten.iter() {
+                        eprintln!(
+                            "Rewrote generation {}...ok ({} entries changed)",
+                            generation, changed
+                        );
+                    }
+                    // Keep the compilation database in use in line with its generation
+                    if let Some(current) = compdb::get_current_generation(&conn)?
+                        && rewritten.iter().any(|(g, n)| *g == current && *n > 0)
+                        && Path::new(COMPDB_FILE).is_file()
+                    {
+                        compdb::remap_compdb(&path_map)?;
+                    }
                     Ok(())
                 }
                 CompdbCmd::Remark { generation, remark } => {
-                    eprint!(
+                    status_line::begin(format!(
                         "Remarking compilation database generation {}...",
                         generation
-                    );
-                    io::stderr().flush()?;
+                    ))?;
                     let rows = compdb::remark_generation(&conn, generation, remark.as_str())?;
                     if rows == 0 {
-                        eprintln!(
-                            "\rRemarking compilation database generation {}...",
+                        status_line::end(format!(
+                            "Remarking compilation database generation {}...",
                             generation
-                        );
+                        ));
                         bail!("No such generation");
                     }
-                    eprintln!(
-                        "\rRemarking compilation database generation {}...ok",
+                    status_line::end(format!(
+                        "Remarking compilation database generation {}...ok",
                         generation
-                    );
+                    ));
                     Ok(())
                 }
             }
@@ -500,6 +1091,10 @@ pub(crate) fn run_app(args: &Cli) -> Result<()> {
                 image_server: final_image_server,
                 nostrip_bins: bins_without_strip,
                 defines: define_map,
+                runner: Runner::new(
+                    conf.runner.as_deref(),
+                    mkinfo_conf.as_ref().and_then(|x| x.runner.as_deref()),
+                ),
             };
 
             let mkinfos = mkinfo::gen_mkinfo(
@@ -1,7 +1,9 @@
 use anstyle::{Ansi256Color, Color, Style};
 use clap::{ArgGroup, Subcommand};
 
-use crate::core::compdb::CompdbEngine;
+use crate::core::compdb::{
+    CompdbEngine, DiffFormat, ListFormat, MergePolicy, SortKey, SystemIncludes,
+};
 
 const STYLE_YELLOW: Style = Style::new().fg_color(Some(Color::Ansi256(Ansi256Color(3))));
 const STYLE_YELLOW_BOLD: Style = Style::new()
@@ -34,6 +36,12 @@ pub(crate) enum CompdbCmd {
   rua compdb gen . a-dnv                                   # For A1000/A2000... under submod dir
   rua compdb gen -e bear . a-dnv                           # For A1000/A2000... under submod dir using bear 
   run compdb gen -e intercept-build . a-dnv                # For A1000/A2000... under submod dir using intercept-build
+  rua compdb gen -e wrapper products/ngfw_as a-dnv         # For A1000/A2000... using rua's own compiler shims
+  rua compdb gen --overlay products/ngfw_as a-dnv          # For A1000/A2000... without modifying makefiles
+  rua compdb gen --headers products/ngfw_as a-dnv          # For A1000/A2000... with entries for headers
+  rua compdb gen --system-includes clangd products/ngfw_as a-dnv # For A1000/A2000... with a .clangd for clangd
+  rua compdb gen --union products/ngfw_as a-dnv hygon      # For both targets, plus the union of them
+  rua compdb gen -6 --product A1000                        # For A1000 with IPv6 support, looked up by mkinfo
 
 {1}Caution:{1:#}
   Some files are modified while running in built-in mode which is the default and faster:
@@ -44,9 +52,39 @@ pub(crate) enum CompdbCmd {
   2. When running under submod dir:
      - scripts/last-rules.mk
      - scripts/rules.mk or scripts/common-rules.mk
-  These files may be left dirty if compdb aborted unexpectedly. You can restore the by executing
-  (make sure you have backed up the changes you made):
-  {2}svn revert Makefile scripts/last-rules.mk scripts/rules.mk scripts/common-rules.mk{2:#}"#,
+  The originals are journaled under .rua before injecting and restored on Ctrl-C. If compdb was
+  killed or crashed, restore them by executing:
+  {2}rua compdb recover{2:#}
+
+{0}Note:{0:#}
+  With --overlay, nothing is modified: $(HS_CC) and $(COMPILE_CXX_CP_E) are overridden on the
+  make command line, and at project root the other prerequisites and the recipe of stoneos-image
+  are skipped. $(HS_CC) turns into the recipe wherever it compiles a .c file into a .o, so C
+  rules outside scripts/last-rules.mk using it are recorded as well. Overlay runs may build
+  concurrently with each other, and wait for a run injecting the makefiles. Writing and archiving
+  compile_commands.json is still done by one run at a time.
+
+  The wrapper engine puts shims for cc/c++/gcc/g++ (configurable by `shims` under [compdb]) on
+  PATH and runs a real build, so neither bear nor intercept-build is needed. The shims link to
+  the rua binary, which has to exist at the same path where the runner builds. Compilers called
+  by absolute path, such as HS_CC set to a full cross-gcc path, bypass the shims and are missed.
+
+  Make runs by `hsdocker7 {{logged_command}}` unless `runner` is set at the top level of the
+  config or under [compdb], where {{command}}, {{cwd}} and {{log}} are replaced, as well as
+  {{logged_command}} by the command redirecting its output to the log, e.g.
+    runner = "docker run --rm -v {{cwd}}:{{cwd}} -w {{cwd}} builder sh -c {{logged_command}}"
+  Set it to "direct" to run make on the host. Inside a container make always runs directly.
+
+  The build log of a failed build is kept under .rua/logs and its error lines are printed.
+  Pass --keep-log to keep it for a successful build as well.
+
+  Several targets are generated one after another, as every engine builds in the same tree, and
+  each is archived as its own generation. The union is archived last, tagged with the targets
+  joined by '+', and gets used.
+
+  For scripted runs, pass --yes or set `no_input = true` at the top level of the config: the
+  store is created without asking and rua never waits on stdin. Progress is written as plain
+  lines when stderr is not a terminal."#,
       STYLE_YELLOW_BOLD,
       STYLE_RED_BOLD,
       STYLE_YELLOW))]
@@ -83,6 +121,37 @@ pub(crate) enum CompdbCmd {
         )]
         intercept_build_path: Option<String>,
 
+        #[arg(
+            long = "overlay",
+            help = "Inject the recipes through make variable overrides instead of editing the makefiles (built-in engine only)"
+        )]
+        overlay: bool,
+
+        #[arg(
+            long = "arguments",
+            help = "Emit tokenized arguments instead of command strings, with response files expanded"
+        )]
+        arguments: bool,
+
+        #[arg(
+            long = "headers",
+            help = "Synthesize entries for headers under the include dirs, using the flags of their includers"
+        )]
+        headers: bool,
+
+        #[arg(
+            long = "system-includes",
+            value_name = "MODE",
+            help = "Pass the builtin include dirs, target and macros of the cross compilers on to clang-based tools"
+        )]
+        system_includes: Option<SystemIncludes>,
+
+        #[arg(
+            long = "keep-log",
+            help = "Keep the build log under .rua/logs even if the build succeeds"
+        )]
+        keep_log: bool,
+
         #[arg(
             long = "merge",
             value_name = "OTHER-COMPDB",
@@ -90,11 +159,115 @@ pub(crate) enum CompdbCmd {
         )]
         merge_seq: Option<Vec<String>>,
 
+        #[arg(
+            long = "merge-policy",
+            value_name = "POLICY",
+            help = "How to settle entries compiling the same file into the same output (defaults to first-wins)"
+        )]
+        merge_policy: Option<MergePolicy>,
+
         #[arg(
             value_name = "PATH",
+            required_unless_present = "product",
             help = "Path for the target where platform-specific makefiles reside, such as 'products/vfw'"
         )]
-        product_dir: String,
+        product_dir: Option<String>,
+
+        #[arg(
+            short = 'p',
+            long = "product",
+            value_name = "NAME",
+            conflicts_with_all = ["product_dir", "make_targets"],
+            help = "Product name like A1000, or a regex like 'X\\d+80', whose make directory and target are looked up by mkinfo"
+        )]
+        product: Option<String>,
+
+        #[arg(
+            short = '6',
+            long = "ipv6",
+            help = "Build the IPv6 variants of the targets, i.e. those suffixed with -ipv6"
+        )]
+        ipv6: bool,
+
+        #[arg(
+            long = "union",
+            help = "Also archive the union of the compilation databases of all targets, tagged with all of them"
+        )]
+        union: bool,
+
+        #[arg(
+            value_name = "TARGET",
+            help = "Targets to build, such as 'a-dnv' (defaults to `targets` under [compdb])"
+        )]
+        make_targets: Vec<String>,
+    },
+
+    /// Regenerate the compilation database for a subdirectory and splice it into the current one
+    ///
+    /// Only the entries for files under the subdirectory are replaced. The result is archived as
+    /// a new generation remarked as derived from the generation in use.
+    #[command(after_help = format!(
+        r#"{0}Examples:{0:#}
+  rua compdb update src/mod a-dnv        # Refresh entries under src/mod
+  rua compdb update -e wrapper src a-dnv # Refresh entries under src using compiler shims"#,
+    STYLE_YELLOW_BOLD
+    ))]
+    Update {
+        #[arg(
+            short = 'D',
+            long = "define",
+            value_name = "KEY=VAL",
+            help = "Define a variable which will be passed to the underlying make command"
+        )]
+        defines: Vec<String>,
+
+        #[arg(
+            short = 'e',
+            long = "engine",
+            value_name = "ENGINE",
+            help = "Engine for generating compilation database (defaults to built-in)"
+        )]
+        engine: Option<CompdbEngine>,
+
+        #[arg(
+            short = 'b',
+            long = "bear-path",
+            value_name = "BEAR",
+            help = "Path to the bear binary (defaults to /devel/sw/bear/bin/bear)"
+        )]
+        bear_path: Option<String>,
+
+        #[arg(
+            short = 'i',
+            long = "intercept-build-path",
+            value_name = "INTERCEPT-BUILD",
+            help = "Path to the intercept-build binary (defaults to /devel/sw/llvm/bin/intercept-build)"
+        )]
+        intercept_build_path: Option<String>,
+
+        #[arg(
+            long = "overlay",
+            help = "Inject the recipes through make variable overrides instead of editing the makefiles (built-in engine only)"
+        )]
+        overlay: bool,
+
+        #[arg(
+            long = "arguments",
+            help = "Emit tokenized arguments instead of command strings, with response files expanded"
+        )]
+        arguments: bool,
+
+        #[arg(
+            long = "keep-log",
+            help = "Keep the build log under .rua/logs even if the build succeeds"
+        )]
+        keep_log: bool,
+
+        #[arg(
+            value_name = "SUBDIR",
+            help = "Make directory to regenerate under, such as 'src/mod'"
+        )]
+        subdir: String,
 
         #[arg(value_name = "TARGET", help = "Target to build, such as 'a-dnv'")]
         make_target: String,
@@ -157,9 +330,94 @@ pub(crate) enum CompdbCmd {
         old: Option<i64>,
     },
 
-    /// List all compilation database generations in store
-    #[command(visible_alias = "list")]
-    Ls,
+    /// Remove generations according to the retention policy under [compdb.retention]
+    ///
+    /// The generation in use is never removed. The store is vacuumed afterwards.
+    #[command(after_help = format!(
+        r#"{0}Examples:{0:#}
+  rua compdb gc           # Remove generations exceeding the retention policy
+  rua compdb gc --dry-run # Show what would be removed only
+
+{0}Note:{0:#}
+  The retention policy is configured under [compdb.retention] like:
+    keep_per_target = 5 # Keep 5 newest generations for each branch and target
+    max_age_days = 90   # Remove generations older than 90 days
+    max_size_mb = 200   # Remove oldest generations until the store fits in 200 MiB
+    auto_gc = true      # Run gc after each gen and add
+  Removing by size stops at the first generation whose removal frees nothing, as entries shared
+  with the generations kept stay in the store."#,
+    STYLE_YELLOW_BOLD
+    ))]
+    Gc {
+        #[arg(
+            short = 'n',
+            long = "dry-run",
+            help = "Show the generations to be removed without removing them"
+        )]
+        dry_run: bool,
+    },
+
+    /// List compilation database generations in store
+    #[command(visible_alias = "list", after_help = format!(
+        r#"{0}Examples:{0:#}
+  rua compdb ls                          # List all generations, newest first
+  rua compdb ls -b master -t a-dnv       # List generations of a-dnv on master
+  rua compdb ls --since 7d --sort size   # List generations of the last week, biggest first
+  rua compdb ls --remark '^derived' -f json  # List generations derived by update in JSON"#,
+    STYLE_YELLOW_BOLD
+    ))]
+    Ls {
+        #[arg(
+            short = 'b',
+            long = "branch",
+            value_name = "BRANCH",
+            help = "Only list generations of the branch"
+        )]
+        branch: Option<String>,
+
+        #[arg(
+            short = 't',
+            long = "target",
+            value_name = "TARGET",
+            help = "Only list generations of the target"
+        )]
+        target: Option<String>,
+
+        #[arg(
+            long = "since",
+            value_name = "TIME",
+            help = "Only list generations added since the time, like 2025-03-01, 2025-03-01T08:00:00 or 7d"
+        )]
+        since: Option<String>,
+
+        #[arg(
+            long = "remark",
+            value_name = "REGEX",
+            help = "Only list generations whose remark matches the regex"
+        )]
+        remark: Option<String>,
+
+        #[arg(
+            short = 's',
+            long = "sort",
+            value_name = "KEY",
+            default_value = "generation",
+            help = "Sort by the key, newest, biggest or alphabetically first"
+        )]
+        sort: SortKey,
+
+        #[arg(short = 'r', long = "reverse", help = "Reverse the order")]
+        reverse: bool,
+
+        #[arg(
+            short = 'f',
+            long = "format",
+            value_name = "FORMAT",
+            default_value = "table",
+            help = "Output format"
+        )]
+        format: ListFormat,
+    },
 
     /// Merge compilation databases into the one in the current directory
     Merge {
@@ -179,14 +437,214 @@ pub(crate) enum CompdbCmd {
         )]
         commit: Option<String>,
 
+        #[arg(
+            long = "arguments",
+            help = "Emit tokenized arguments instead of command strings, with response files expanded"
+        )]
+        arguments: bool,
+
+        #[arg(
+            long = "merge-policy",
+            value_name = "POLICY",
+            help = "How to settle entries compiling the same file into the same output (defaults to first-wins)"
+        )]
+        merge_policy: Option<MergePolicy>,
+
         #[arg(value_name = "FILE", help = "Compilation database to be joined")]
         files: Vec<String>,
     },
 
+    /// Restore makefiles left injected by an aborted built-in run of gen
+    Recover,
+
     /// Select a compilation database generation from store to use
+    #[command(group = ArgGroup::new("which").args(["generation", "auto"]).required(true),
+        after_help = format!(
+        r#"{0}Examples:{0:#}
+  rua compdb use 3                 # Use generation 3
+  rua compdb use --auto            # Use the generation closest to the current commit
+  rua compdb use --auto -t a-dnv   # Same as above but for a-dnv only
+
+{0}Note:{0:#}
+  With --auto, the generation is picked among those of the current branch by the distance of its
+  commit to the current one, which is the number of commits in between for git and the difference
+  of revisions for svn. Run `rua compdb hook` to do this after each branch switch, which takes
+  effect when `auto_use = true` is set under [compdb].
+
+  The rules under [compdb.flags] are applied to the compilation database in use, while the store
+  keeps the original commands. Use the current generation again after changing them:
+    remove = ["^-fno-var-tracking-assignments$", "^-fplugin"] # Drop flags matching the regexes
+    replace = {{ "^-mtune=.*$" = "-mtune=generic" }}          # Rewrite flags matching the regexes
+    append = ["-Wno-unknown-warning-option"]                  # Add flags to each command"#,
+    STYLE_YELLOW_BOLD
+    ))]
     Use {
         #[arg(value_name = "GENERATION", help = "Compilation database generation id")]
-        generation: i64,
+        generation: Option<i64>,
+
+        #[arg(
+            short = 'a',
+            long = "auto",
+            help = "Pick the generation matching the current branch and closest to the current commit"
+        )]
+        auto: bool,
+
+        #[arg(
+            short = 't',
+            long = "target",
+            value_name = "TARGET",
+            requires = "auto",
+            help = "Only pick among generations for this target"
+        )]
+        target: Option<String>,
+
+        #[arg(long = "from-hook", hide = true, requires = "auto")]
+        from_hook: bool,
+    },
+
+    /// Install a git post-checkout hook which runs `rua compdb use --auto` after branch switches
+    Hook {
+        #[arg(long = "uninstall", help = "Remove the hook installed")]
+        uninstall: bool,
+    },
+
+    /// Compare the compilation databases of two generations
+    #[command(after_help = format!(
+        r#"{0}Examples:{0:#}
+  rua compdb diff 3 5               # Show files and flags changed from generation 3 to 5
+  rua compdb diff --format json 3 5 # Same as above but in JSON"#,
+    STYLE_YELLOW_BOLD
+    ))]
+    Diff {
+        #[arg(
+            short = 'f',
+            long = "format",
+            value_name = "FORMAT",
+            default_value = "text",
+            help = "Output format"
+        )]
+        format: DiffFormat,
+
+        #[arg(value_name = "GENERATION-A", help = "The generation to compare from")]
+        from: i64,
+
+        #[arg(value_name = "GENERATION-B", help = "The generation to compare to")]
+        to: i64,
+    },
+
+    /// Map path prefixes of stored generations in place
+    ///
+    /// Paths are mapped in directory, file and output as well as in the command or arguments,
+    /// such as include dirs. The compilation database in use is rewritten along with its
+    /// generation.
+    #[command(group = ArgGroup::new("which").args(["generations", "all"]).required(true),
+        after_help = format!(
+        r#"{0}Examples:{0:#}
+  rua compdb rewrite --map /work=/home/user/src/stoneos 3 # Map /work to the host path in generation 3
+  rua compdb rewrite --all                                # Apply [compdb.path_map] to all generations
+
+{0}Note:{0:#}
+  Path mappings can be configured under [compdb.path_map], which are applied by gen, merge and use:
+    "/work" = "/home/user/src/stoneos"
+  Longer prefixes take precedence, and a prefix only matches whole path components."#,
+    STYLE_YELLOW_BOLD
+    ))]
+    Rewrite {
+        #[arg(
+            short = 'm',
+            long = "map",
+            value_name = "FROM=TO",
+            help = "Map the path prefix FROM to TO (defaults to mappings under [compdb.path_map])"
+        )]
+        maps: Vec<String>,
+
+        #[arg(short = 'a', long = "all", help = "Rewrite all generations")]
+        all: bool,
+
+        #[arg(value_name = "GENERATION", help = "Generations to rewrite")]
+        generations: Vec<i64>,
+    },
+
+    /// Check a compilation database for entries which would mislead language servers
+    ///
+    /// Errors are reported for missing files or directories, compilers which the runner can't
+    /// find and commands left mangled by the built-in engine. Warnings are reported for files
+    /// listed more than once and flags unusual among the files in the same directory.
+    #[command(after_help = format!(
+        r#"{0}Examples:{0:#}
+  rua compdb check                          # Check compile_commands.json in the current directory
+  rua compdb check path/to/compile_commands.json
+  rua compdb check -g 3                     # Check generation 3 in store
+  rua compdb check && rua compdb add all    # Archive only if no error is found
+
+{0}Note:{0:#}
+  It exits with non-zero status if any error is found."#,
+    STYLE_YELLOW_BOLD
+    ))]
+    Check {
+        #[arg(
+            short = 'g',
+            long = "generation",
+            value_name = "GENERATION",
+            conflicts_with = "file",
+            help = "Check the generation in store instead of a file"
+        )]
+        generation: Option<i64>,
+
+        #[arg(
+            value_name = "FILE",
+            default_value = "compile_commands.json",
+            help = "The compilation database to check"
+        )]
+        file: String,
+    },
+
+    /// Export generations from store into a portable bundle
+    #[command(after_help = format!(
+        r#"{0}Examples:{0:#}
+  rua compdb export 3 -o a-dnv.rcdb   # Export generation 3
+  rua compdb export 3 5 -o both.rcdb  # Export generations 3 and 5"#,
+    STYLE_YELLOW_BOLD
+    ))]
+    Export {
+        #[arg(
+            value_name = "GENERATION",
+            required = true,
+            help = "Generations to export"
+        )]
+        generations: Vec<i64>,
+
+        #[arg(
+            short = 'o',
+            long = "output",
+            value_name = "FILE",
+            help = "Bundle to write, such as 'a-dnv.rcdb'"
+        )]
+        output: String,
+    },
+
+    /// Import generations from a bundle into store
+    #[command(after_help = format!(
+        r#"{0}Examples:{0:#}
+  rua compdb import a-dnv.rcdb          # Import as is
+  rua compdb import --rebase a-dnv.rcdb # Import with paths rebased onto this workspace"#,
+    STYLE_YELLOW_BOLD
+    ))]
+    Import {
+        #[arg(
+            long = "rebase",
+            help = "Rebase the absolute paths in the bundle onto the current workspace"
+        )]
+        rebase: bool,
+
+        #[arg(value_name = "FILE", help = "Bundle to import")]
+        bundle: String,
+    },
+
+    /// Inspect and maintain the compilation database store
+    Store {
+        #[command(subcommand)]
+        store_comm: StoreCmd,
     },
 
     /// Remark a compilation database generation
@@ -204,3 +662,9 @@ pub(crate) enum CompdbCmd {
         remark: String,
     },
 }
+
+#[derive(Clone, Debug, Subcommand)]
+pub(crate) enum StoreCmd {
+    /// Show schema version, generation count and size of the store
+    Info,
+}
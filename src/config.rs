@@ -5,12 +5,14 @@ use config::Config;
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
+use crate::core::compdb::MergePolicy;
 use crate::utils::RepoInfo;
 
 pub(crate) const COMPDB_FILE: &str = "compile_commands.json";
 pub(crate) const COMPDB_STORE: &str = ".rua/compdb.store";
 pub(crate) const DEFAULT_BEAR: &str = "/devel/sw/bear/bin/bear";
 pub(crate) const DEFAULT_INTERCEPT_BUILD: &str = "/devel/sw/llvm/bin/intercept-build";
+pub(crate) const DEFAULT_COMPILER_SHIMS: [&str; 4] = ["cc", "c++", "gcc", "g++"];
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct CleanConf {
@@ -28,6 +30,7 @@ impl CleanConf {
 pub(crate) struct MkinfoConf {
     pub(crate) image_server: Option<String>,
     pub(crate) defines: Option<IndexMap<String, String>>,
+    pub(crate) runner: Option<String>,
 }
 
 impl MkinfoConf {
@@ -36,6 +39,7 @@ impl MkinfoConf {
         MkinfoConf {
             image_server: None,
             defines: None,
+            runner: None,
         }
     }
 }
@@ -54,13 +58,63 @@ impl ReviewConf {
     }
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct RetentionConf {
+    pub(crate) keep_per_target: Option<usize>,
+    pub(crate) max_age_days: Option<u64>,
+    pub(crate) max_size_mb: Option<u64>,
+    pub(crate) auto_gc: Option<bool>,
+}
+
+impl RetentionConf {
+    #[allow(dead_code)]
+    pub(crate) fn new() -> RetentionConf {
+        RetentionConf {
+            keep_per_target: None,
+            max_age_days: None,
+            max_size_mb: None,
+            auto_gc: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct FlagsConf {
+    pub(crate) remove: Option<Vec<String>>,
+    pub(crate) replace: Option<IndexMap<String, String>>,
+    pub(crate) append: Option<Vec<String>>,
+}
+
+impl FlagsConf {
+    #[allow(dead_code)]
+    pub(crate) fn new() -> FlagsConf {
+        FlagsConf {
+            remove: None,
+            replace: None,
+            append: None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct CompdbConf {
     pub(crate) defines: Option<IndexMap<String, String>>,
     pub(crate) engine: Option<String>,
     pub(crate) bear_path: Option<String>,
     pub(crate) intercept_build_path: Option<String>,
+    pub(crate) shims: Option<Vec<String>>,
+    pub(crate) overlay: Option<bool>,
+    pub(crate) arguments: Option<bool>,
     pub(crate) merge: Option<Vec<String>>,
+    pub(crate) merge_policy: Option<MergePolicy>,
+    pub(crate) auto_use: Option<bool>,
+    pub(crate) retention: Option<RetentionConf>,
+    pub(crate) path_map: Option<IndexMap<String, String>>,
+    pub(crate) headers: Option<bool>,
+    pub(crate) system_includes: Option<String>,
+    pub(crate) flags: Option<FlagsConf>,
+    pub(crate) runner: Option<String>,
+    pub(crate) targets: Option<Vec<String>>,
 }
 
 impl CompdbConf {
@@ -71,13 +125,27 @@ impl CompdbConf {
             engine: None,
             bear_path: None,
             intercept_build_path: None,
+            shims: None,
+            overlay: None,
+            arguments: None,
             merge: None,
+            merge_policy: None,
+            auto_use: None,
+            retention: None,
+            path_map: None,
+            headers: None,
+            system_includes: None,
+            flags: None,
+            runner: None,
+            targets: None,
         }
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct RuaConf {
+    pub(crate) runner: Option<String>,
+    pub(crate) no_input: Option<bool>,
     pub(crate) clean: Option<CleanConf>,
     pub(crate) mkinfo: Option<MkinfoConf>,
     pub(crate) review: Option<ReviewConf>,
@@ -120,6 +188,7 @@ impl RuaConf {
             )
             .build()?;
 
-        Ok(s.try_deserialize().unwrap())
+        s.try_deserialize()
+            .context("Failed to parse rua configuration")
     }
 }
@@ -4,10 +4,10 @@ use std::{env, fs};
 
 use anyhow::{Context, bail};
 use globset::GlobSet;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::ProgressStyle;
 use regex::Regex;
 
-use crate::utils::progress_bar::{TICK_CHARS, TICK_INTERVAL};
+use crate::utils::progress_bar::{self, TICK_CHARS, TICK_INTERVAL};
 use crate::utils::{RepoInfo, RepoType, normalize_path};
 
 fn svn_untracked_files(dirs: Vec<&str>) -> anyhow::Result<Vec<PathBuf>> {
@@ -15,7 +15,7 @@ fn svn_untracked_files(dirs: Vec<&str>) -> anyhow::Result<Vec<PathBuf>> {
         .arg("status")
         .args(dirs.iter())
         .output()
-        .context(format!("Command `svn status` failed"))?;
+        .context("Command `svn status` failed")?;
     if !output.status.success() {
         bail!("Command `svn status {:?}` failed", dirs.join(" "));
     }
@@ -110,14 +110,13 @@ pub fn clean_build(
     }
 
     // Clean untracked files
-    let pb = ProgressBar::no_length().with_style(
-        ProgressStyle::with_template(&format!("Fetching untracked files {{spinner}}"))?
-            .tick_chars(TICK_CHARS),
+    let pb = progress_bar::no_length().with_style(
+        ProgressStyle::with_template("Fetching untracked files {spinner}")?.tick_chars(TICK_CHARS),
     );
     pb.enable_steady_tick(TICK_INTERVAL);
     let dirs: Vec<String> = dirs.map_or(Vec::new(), |x| x.clone());
     let untracked_files = get_untracked_files(
-        &repo_info,
+        repo_info,
         dirs.iter().map(|x| x.as_str()).collect::<Vec<&str>>(),
     )?;
     pb.finish_and_clear();
@@ -126,7 +125,7 @@ pub fn clean_build(
             entry
                 .as_path()
                 .to_str()
-                .expect(format!("Failed to convert {:?} to str", entry).as_str()),
+                .unwrap_or_else(|| panic!("Failed to convert {:?} to str", entry)),
         ) {
             continue;
         }
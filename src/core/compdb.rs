@@ -1,35 +1,48 @@
 use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::hash_map::Entry;
 use std::env;
 use std::fmt;
 use std::fs;
 use std::io;
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
 use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::Duration;
 
 use anstyle::{Ansi256Color, Color, Style};
 use anyhow::{Context, bail};
 use chrono::TimeZone;
 use clap::ValueEnum;
 use indexmap::IndexMap;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanDuration, ProgressBar, ProgressStyle};
 use regex::Regex;
-use rusqlite::{self, Connection, OptionalExtension, params, params_from_iter};
+use rusqlite::{
+    self, Connection, OptionalExtension, TransactionBehavior, params, params_from_iter,
+};
 use serde::{Deserialize, Serialize};
-use serde_json::{self, json};
+use serde_json;
+use sha2::{Digest, Sha256};
 use zstd::{decode_all, encode_all};
 
 use crate::config::{COMPDB_FILE, DEFAULT_BEAR, DEFAULT_INTERCEPT_BUILD};
-use crate::utils::RepoInfo;
-use crate::utils::progress_bar::{TICK_CHARS, TICK_INTERVAL};
+use crate::core::showcc::CommandOrArguments;
+use crate::utils::progress_bar::{self, TICK_CHARS, TICK_INTERVAL};
+use crate::utils::{self, RepoInfo, Runner, normalize_path, status_line};
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum)]
 pub(crate) enum CompdbEngine {
     BuiltIn,
     InterceptBuild,
     Bear,
+    Wrapper,
 }
 
 impl fmt::Display for CompdbEngine {
@@ -38,17 +51,28 @@ impl fmt::Display for CompdbEngine {
             Self::BuiltIn => write!(f, "built-in"),
             Self::InterceptBuild => write!(f, "intercept-build"),
             Self::Bear => write!(f, "bear"),
+            Self::Wrapper => write!(f, "wrapper"),
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug)]
 pub(crate) struct CompdbOptions {
     pub(crate) defines: IndexMap<String, String>,
     pub(crate) engine: Option<CompdbEngine>,
     pub(crate) intercept_build_path: Option<PathBuf>,
     pub(crate) bear_path: Option<PathBuf>,
+    pub(crate) shims: Vec<String>,
+    pub(crate) overlay: bool,
+    pub(crate) arguments: bool,
     pub(crate) to_merge: Vec<PathBuf>,
+    pub(crate) merge_policy: MergePolicy,
+    pub(crate) path_map: Vec<(String, String)>,
+    pub(crate) headers: bool,
+    pub(crate) system_includes: Option<SystemIncludes>,
+    pub(crate) runner: Runner,
+    pub(crate) expected_entries: Option<usize>,
+    pub(crate) keep_log: bool,
 }
 
 impl fmt::Display for CompdbOptions {
@@ -60,20 +84,116 @@ impl fmt::Display for CompdbOptions {
     engine: {:?}
     intercept_build_path: {:?}
     bear_path: {:?}
+    shims: {:?}
+    overlay: {:?}
+    arguments: {:?}
+    merge_policy: {}
+    path_map: {:?}
+    headers: {:?}
+    system_includes: {:?}
+    runner: {:?}
+    expected_entries: {:?}
+    keep_log: {}
 }}"#,
             serde_json::to_string_pretty(&self.defines),
             self.engine,
             self.intercept_build_path,
-            self.bear_path
+            self.bear_path,
+            self.shims,
+            self.overlay,
+            self.arguments,
+            self.merge_policy,
+            self.path_map,
+            self.headers,
+            self.system_includes,
+            self.runner,
+            self.expected_entries,
+            self.keep_log
         )
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct CompdbRecord {
-    pub command: String,
     pub directory: String,
+    #[serde(flatten)]
+    pub compile_command: CommandOrArguments,
     pub file: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+impl CompdbRecord {
+    /// The compile command as a single shell command line
+    pub(crate) fn command(&self) -> String {
+        match &self.compile_command {
+            CommandOrArguments::Command { command } => command.clone(),
+            CommandOrArguments::Arguments { arguments } => arguments
+                .iter()
+                .map(|x| utils::shell_quote(x))
+                .collect::<Vec<String>>()
+                .join(" "),
+        }
+    }
+
+    /// The compile command split into words
+    pub(crate) fn arguments(&self) -> anyhow::Result<Vec<String>> {
+        match &self.compile_command {
+            CommandOrArguments::Command { command } => utils::shell_split(command),
+            CommandOrArguments::Arguments { arguments } => Ok(arguments.clone()),
+        }
+    }
+
+    /// Identity of the compilation, made of the normalized directory, file and output. The
+    /// output comes from the `output` field or else the `-o` argument of the command.
+    fn merge_key(&self) -> (PathBuf, PathBuf, Option<PathBuf>) {
+        let directory = normalize_path(&self.directory);
+        let file = normalize_path(directory.join(&self.file));
+        let output = match self.output.as_ref() {
+            Some(v) => Some(v.clone()),
+            None => {
+                let args = self.arguments().unwrap_or_default();
+                let mut output = None;
+                for (i, arg) in args.iter().enumerate() {
+                    if arg == "-o" {
+                        output = args.get(i + 1).cloned();
+                    } else if let Some(v) = joined_output(arg) {
+                        output = Some(v.to_string());
+                    }
+                }
+                output
+            }
+        };
+        let output = output.map(|x| normalize_path(directory.join(x)));
+        (directory, file, output)
+    }
+
+    /// Number of macros defined on the command line
+    fn num_defines(&self) -> usize {
+        self.arguments()
+            .unwrap_or_default()
+            .iter()
+            .filter(|x| x.starts_with("-D"))
+            .count()
+    }
+}
+
+/// Options starting with `-o` that are not the output joined with its path
+const NON_OUTPUT_O_OPTIONS: [&str; 4] = ["-objc", "-object", "-openmp", "-order_file"];
+
+/// Optimization levels mistyped in lower case, which are no output paths either
+const LOWERCASE_OPTIMIZATIONS: [&str; 8] =
+    ["-o0", "-o1", "-o2", "-o3", "-os", "-oz", "-og", "-ofast"];
+
+/// Output path of an argument in the joined `-o<path>` form, or None for any other argument
+fn joined_output(arg: &str) -> Option<&str> {
+    let output = arg.strip_prefix("-o").filter(|x| !x.is_empty())?;
+    if NON_OUTPUT_O_OPTIONS.iter().any(|x| arg.starts_with(x))
+        || LOWERCASE_OPTIMIZATIONS.contains(&arg)
+    {
+        return None;
+    }
+    Some(output)
 }
 
 impl fmt::Display for CompdbRecord {
@@ -81,157 +201,467 @@ impl fmt::Display for CompdbRecord {
         write!(
             f,
             r#"{{ command: {}, directory: {}, file: {} }}"#,
-            self.command, self.directory, self.file
+            self.command(),
+            self.directory,
+            self.file
         )
     }
 }
 
-const BUILDLOG_PATH: &str = ".rua.compdb.tmp";
+const BUILDLOG_DIR: &str = ".rua/logs";
+/// Number of error lines shown from the log of a failed build
+const BUILDLOG_ERRORS_SHOWN: usize = 10;
 
-pub(crate) fn gen_compdb_by_builtin(
-    repoinfo: &RepoInfo,
-    make_directory: &str,
+const CORE_RULE_PATTERN: &str = r#"(?m)^((?:\s*[^:\s]*\s+)*stoneos-image(?:\s+[^:\s]*)*):(.*)$"#;
+const JOURNAL_PATH: &str = ".rua/compdb.journal";
+const FILTERED_DIGEST_PATH: &str = ".rua/compdb.filtered";
+const INJECTION_LOCK_PATH: &str = ".rua/compdb.lock";
+const OUTPUT_LOCK_PATH: &str = ".rua/compdb.output.lock";
+const INJECTION_MARKER: &str = "##JCDB##";
+const INJECTABLE_MAKEFILES: [&str; 4] = [
+    "scripts/last-rules.mk",
+    "scripts/rules.mk",
+    "scripts/common-rules.mk",
+    "Makefile",
+];
+
+/// Original content of a makefile, saved to the journal before the makefile gets injected
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct JournalEntry {
+    file: PathBuf,
+    orig: String,
+}
+
+/// What counts as a compile unit while following the build log
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum UnitKind {
+    /// Records printed by the injected recipes
+    Record,
+    /// Compiler invocations echoed by make
+    Invocation,
+}
+
+/// Build log followed while make is running, for showing the progress of the build live
+struct BuildLogTail {
+    path: PathBuf,
+    offset: u64,
+    partial: String,
+    kind: UnitKind,
+    units: usize,
+    directory: Option<String>,
+    expected: Option<usize>,
+    pattern_directory: Regex,
+}
+
+impl BuildLogTail {
+    fn new<T: AsRef<Path>>(path: T, kind: UnitKind, expected: Option<usize>) -> BuildLogTail {
+        BuildLogTail {
+            path: path.as_ref().to_path_buf(),
+            offset: 0,
+            partial: String::new(),
+            kind,
+            units: 0,
+            directory: None,
+            expected: expected.filter(|x| *x > 0),
+            pattern_directory: Regex::new(r#"make(?:\[\d+\])?: Entering directory [`'](.+)'"#)
+                .unwrap(),
+        }
+    }
+
+    /// Consume what has been appended to the log since the last poll. The log may not have been
+    /// created yet, or be written by a runner without us knowing, so errors are ignored.
+    fn poll(&mut self) {
+        let Ok(mut file) = fs::File::open(&self.path) else {
+            return;
+        };
+        let mut buf = Vec::new();
+        if file.seek(io::SeekFrom::Start(self.offset)).is_err()
+            || file.read_to_end(&mut buf).is_err()
+        {
+            return;
+        }
+        self.offset += buf.len() as u64;
+        self.partial.push_str(&String::from_utf8_lossy(&buf));
+        let Some(end) = self.partial.rfind('\n') else {
+            return;
+        };
+        let complete: String = self.partial.drain(..=end).collect();
+        for line in complete.lines() {
+            if let Some(captures) = self.pattern_directory.captures(line) {
+                self.directory = Some(captures.get(1).unwrap().as_str().to_string());
+            }
+            let counted = match self.kind {
+                UnitKind::Record => line.starts_with(INJECTION_MARKER),
+                UnitKind::Invocation => {
+                    let words: Vec<&str> = line.split_whitespace().collect();
+                    words.contains(&"-c") && words.iter().any(|x| is_source_file(x))
+                }
+            };
+            if counted {
+                self.units += 1;
+            }
+        }
+    }
+
+    /// Units captured so far, with an ETA extrapolated from the units of the previous generation,
+    /// and the directory make is working in
+    fn message(&self, elapsed: Duration) -> String {
+        let noun = match self.kind {
+            UnitKind::Record => "records",
+            UnitKind::Invocation => "compiler invocations",
+        };
+        let mut message = match self.expected {
+            Some(expected) if self.units > 0 && self.units < expected => {
+                let remaining = elapsed.mul_f64((expected - self.units) as f64 / self.units as f64);
+                format!(
+                    "{}/~{} {}, ETA {}",
+                    self.units,
+                    expected,
+                    noun,
+                    HumanDuration(remaining)
+                )
+            }
+            _ => format!("{} {}", self.units, noun),
+        };
+        if let Some(directory) = self.directory.as_ref() {
+            let cwd = env::current_dir().unwrap_or_default();
+            let directory = Path::new(directory);
+            let directory = directory.strip_prefix(&cwd).unwrap_or(directory);
+            if !directory.as_os_str().is_empty() {
+                message.push_str(&format!(", in {}", directory.display()));
+            }
+        }
+        message
+    }
+}
+
+/// Build log of this run under the repo root. Each run gets its own, so that concurrent runs of
+/// any engine never write, parse or remove each other's.
+fn run_buildlog(repo_root: &Path) -> PathBuf {
+    repo_root.join(format!(".rua.compdb.{}.tmp", std::process::id()))
+}
+
+/// Move the build log under .rua/logs, named after the current time and the make target. The
+/// build logs are written at the repo root, so .rua/logs is looked up next to the build log
+/// rather than in the current directory, which is a subdirectory for update.
+fn keep_buildlog<T: AsRef<Path>>(buildlog: T, make_target: &str) -> anyhow::Result<PathBuf> {
+    let logs_dir = buildlog
+        .as_ref()
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(BUILDLOG_DIR);
+    fs::create_dir_all(&logs_dir).context(format!("Failed to create {}", logs_dir.display()))?;
+    let stem = format!(
+        "{}-{}",
+        chrono::Local::now().format("%Y%m%dT%H%M%S"),
+        make_target.replace('/', "_")
+    );
+    let mut kept = logs_dir.join(format!("{}.log", stem));
+    let mut n = 1;
+    while kept.exists() {
+        kept = logs_dir.join(format!("{}.{}.log", stem, n));
+        n += 1;
+    }
+    fs::rename(buildlog.as_ref(), &kept).context(format!(
+        "Failed to move {} to {}",
+        buildlog.as_ref().display(),
+        kept.display()
+    ))?;
+    Ok(kept)
+}
+
+/// Be done with the build log of a successful build, keeping it when asked to
+fn dispose_buildlog<T: AsRef<Path>>(
+    buildlog: T,
     make_target: &str,
-    macros: &IndexMap<String, String>,
+    keep: bool,
 ) -> anyhow::Result<()> {
-    #[allow(unused)]
-    struct ChangedFile<'a> {
-        file: &'a Path,
-        orig: String,
-        post: String,
+    if keep {
+        let kept = keep_buildlog(buildlog, make_target)?;
+        eprintln!("Build log kept at {}", kept.display());
+    } else {
+        fs::remove_file(buildlog.as_ref())
+            .context(format!("Failed to remove {}", buildlog.as_ref().display()))?;
     }
+    Ok(())
+}
 
-    const NSTEPS: usize = 5;
-
-    // Invoke svn firstly to check whether we are in a working copy
-    let repo_root = PathBuf::from(repoinfo.work_dir());
-    let at_proj_root = env::current_dir()? == repo_root;
+/// Pick the lines telling why the build failed, i.e. make's `***` lines and the compilers'
+/// `error:` lines, along with the directory make was working in for each
+fn summarize_buildlog(log: &str) -> Vec<(Option<String>, String)> {
+    let pattern_directory =
+        Regex::new(r#"^make(?:\[(\d+)\])?: (Entering|Leaving) directory [`'](.+)'"#).unwrap();
+    let pattern_make_error = Regex::new(r#"^make(?:\[(\d+)\])?: \*\*\*"#).unwrap();
+    // Directories by make level, which stay distinguishable when parallel jobs interleave
+    let mut directories: HashMap<String, String> = HashMap::new();
+    let mut current: Option<String> = None;
+    let mut errors = Vec::new();
+    for line in log.lines() {
+        if let Some(captures) = pattern_directory.captures(line) {
+            let level = captures.get(1).map_or("0", |x| x.as_str()).to_string();
+            let directory = captures.get(3).unwrap().as_str().to_string();
+            if captures.get(2).unwrap().as_str() == "Entering" {
+                directories.insert(level, directory.clone());
+                current = Some(directory);
+            } else {
+                directories.remove(&level);
+            }
+        } else if let Some(captures) = pattern_make_error.captures(line) {
+            let level = captures.get(1).map_or("0", |x| x.as_str());
+            errors.push((directories.get(level).cloned(), line.to_string()));
+        } else if line.contains("error:") {
+            errors.push((current.clone(), line.to_string()));
+        }
+    }
+    errors
+}
 
-    let lastrules_path = repo_root.join("scripts/last-rules.mk");
-    let rules_path = repo_root.join("scripts/rules.mk");
-    let common_rules_path = repo_root.join("scripts/common-rules.mk");
-    let top_makefile = repo_root.join("Makefile");
+/// Keep the log of a failed build and show the last lines telling why it failed, or merely the
+/// last lines if none does, such as when the runner itself failed
+fn report_failed_build<T: AsRef<Path>>(buildlog: T, make_target: &str) -> anyhow::Result<()> {
+    let log = fs::read(buildlog.as_ref()).unwrap_or_default();
+    let log = String::from_utf8_lossy(&log);
+    let mut errors = summarize_buildlog(&log);
+    if errors.is_empty() {
+        errors = log
+            .lines()
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| (None, x.to_string()))
+            .collect();
+    }
+    let kept = keep_buildlog(buildlog, make_target)?;
+    let cwd = env::current_dir()?;
+    let mut last_directory = None;
+    for (directory, line) in errors[errors.len().saturating_sub(BUILDLOG_ERRORS_SHOWN)..].iter() {
+        if directory.is_some() && *directory != last_directory {
+            let directory = Path::new(directory.as_ref().unwrap());
+            let directory = directory.strip_prefix(&cwd).unwrap_or(directory);
+            eprintln!(
+                "In {}:",
+                if directory.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    directory
+                }
+                .display()
+            );
+        }
+        last_directory = directory.clone();
+        eprintln!("  {}", line);
+    }
+    eprintln!("Build log kept at {}", kept.display());
+    Ok(())
+}
 
-    if !lastrules_path.is_file() {
-        bail!(r#"File not found: "{}""#, lastrules_path.display());
+/// Wait for the child process to exit, refreshing the progress bar from the build log meanwhile
+fn wait_following_log(
+    child: &mut std::process::Child,
+    pb: &ProgressBar,
+    tail: &mut BuildLogTail,
+) -> anyhow::Result<std::process::ExitStatus> {
+    loop {
+        tail.poll();
+        pb.set_message(tail.message(pb.elapsed()));
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        thread::sleep(TICK_INTERVAL);
     }
-    if !rules_path.is_file() {
-        bail!(r#"File not found: "{}""#, rules_path.display());
+}
+
+/// Take the advisory lock which keeps concurrent runs from injecting the makefiles of the same
+/// work dir, waiting for the holder if asked to, or failing otherwise. Runs reading the makefiles
+/// without injecting take it shared. The lock is released when the returned file is dropped.
+fn lock_injection(repo_root: &Path, wait: bool, shared: bool) -> anyhow::Result<fs::File> {
+    let path = repo_root.join(INJECTION_LOCK_PATH);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .context(format!("Failed to open {}", path.display()))?;
+    let locked = if shared {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match locked {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) if wait => {
+            eprintln!("Waiting for another run to finish injecting the makefiles...");
+            if shared {
+                file.lock_shared()
+            } else {
+                file.lock()
+            }
+            .context(format!("Failed to lock {}", path.display()))?;
+        }
+        Err(fs::TryLockError::WouldBlock) => {
+            bail!("The makefiles are being injected by another run, wait for it to finish")
+        }
+        Err(fs::TryLockError::Error(e)) => {
+            return Err(e).context(format!("Failed to lock {}", path.display()));
+        }
     }
-    if !common_rules_path.is_file() {
-        bail!(r#"File not found: "{}""#, common_rules_path.display());
+    Ok(file)
+}
+
+/// Advisory lock held from writing the compilation database in the current directory until it
+/// is archived, so that concurrent runs never archive or rewrite the output of one another
+pub(crate) struct OutputLock(#[allow(dead_code)] fs::File);
+
+/// Take the output lock of the work dir, waiting for the holder
+pub(crate) fn lock_output(repo_info: &RepoInfo) -> anyhow::Result<OutputLock> {
+    let path = Path::new(repo_info.work_dir()).join(OUTPUT_LOCK_PATH);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .context(format!("Failed to open {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => {
+            eprintln!("Waiting for another run to finish writing the compilation database...");
+            file.lock()
+                .context(format!("Failed to lock {}", path.display()))?;
+        }
+        Err(fs::TryLockError::Error(e)) => {
+            return Err(e).context(format!("Failed to lock {}", path.display()));
+        }
     }
-    if at_proj_root && !top_makefile.is_file() {
-        bail!(r#"File not found: "{}""#, top_makefile.display());
+    Ok(OutputLock(file))
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_signum: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Defer SIGINT and SIGTERM so that an interrupted run still restores the injected makefiles.
+/// The pseudo build is terminated by the signal as usual since handlers are reset on exec.
+fn defer_interrupts() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    let handler = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
     }
+}
 
-    let mut step: usize = 1;
+/// Reinstall the default handlers, returns whether an interrupt arrived in the meantime
+fn undefer_interrupts() -> bool {
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+        libc::signal(libc::SIGTERM, libc::SIG_DFL);
+    }
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
 
-    let pb1 = ProgressBar::no_length().with_style(
-        ProgressStyle::with_template(
-            format!("[{}/{}] Injecting mkfiles {{spinner:.green}}", step, NSTEPS).as_str(),
-        )?
-        .tick_chars(TICK_CHARS),
-    );
-    pb1.enable_steady_tick(TICK_INTERVAL);
+/// Save the original makefiles into the journal. The journal is written to a temporary file
+/// first so that a crash never leaves a truncated journal behind.
+fn write_journal(journal: &Path, entries: &[JournalEntry]) -> anyhow::Result<()> {
+    if let Some(parent) = journal.parent() {
+        fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+    }
+    let tmp = journal.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string(entries)?)
+        .context(format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, journal).context(format!("Failed to write {}", journal.display()))?;
+    Ok(())
+}
 
-    let mut changed_files: Vec<ChangedFile> = Vec::new();
+/// Restore the makefiles saved in the journal and drop the journal. Files which are already
+/// intact are left untouched to keep their mtime. Returns the files restored.
+fn restore_from_journal(journal: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let text =
+        fs::read_to_string(journal).context(format!("Failed to read {}", journal.display()))?;
+    let entries: Vec<JournalEntry> =
+        serde_json::from_str(&text).context(format!("Failed to parse {}", journal.display()))?;
+    let mut restored = Vec::new();
+    for entry in entries {
+        if fs::read_to_string(&entry.file).ok().as_deref() == Some(entry.orig.as_str()) {
+            continue;
+        }
+        fs::write(&entry.file, &entry.orig)
+            .context(format!("Failed to restore {}", entry.file.display()))?;
+        restored.push(entry.file);
+    }
+    fs::remove_file(journal).context(format!("Failed to remove {}", journal.display()))?;
+    Ok(restored)
+}
 
-    // Hacking for c files
+fn inject_makefiles(
+    entries: &[JournalEntry],
+    lastrules_path: &Path,
+    top_makefile: Option<&Path>,
+) -> anyhow::Result<Vec<PathBuf>> {
     let pattern_c = Regex::new(r#"(?m)^\t\s*\$\(HS_CC\)\s+(\$\(CFLAGS\w*\)\s+\$\(CFLAGS\w*\)\s+-MMD(?:\s+-MP\s+-MT\s+\$@)?(?:\s+-MT\s+.*tidy\.log)?\s+-c\s+-o\s+\$@\s+\$<)\s*$"#)
         .context("Failed to build regex for C compilation")?;
-    let lastrules_text = fs::read_to_string(lastrules_path.as_path())
-        .context(format!(r#"Can't read file "{}""#, lastrules_path.display()))?;
-    let captures = pattern_c
-        .captures(&lastrules_text)
-        .context(format!("Failed to capture pattern {}", pattern_c.as_str()))?;
-    let comp_args_c = captures.get(1).unwrap().as_str();
-    let lastrules_text_hacked = pattern_c.replace_all(&lastrules_text, format!("\t##JCDB## >>:directory:>> $(shell pwd | sed -z 's/\\n//g') >>:command:>> $(CC) {} >>:file:>> $<", comp_args_c)).to_string();
-    fs::write(lastrules_path.as_path(), &lastrules_text_hacked).context(format!(
-        r#"Writing to file "{}" failed"#,
-        lastrules_path.display()
-    ))?;
-    changed_files.push(ChangedFile {
-        file: &lastrules_path,
-        orig: lastrules_text,
-        post: lastrules_text_hacked,
-    });
-
-    // Hacking for cxx files
     let pattern_cxx = Regex::new(r#"(?m)^\t[[:blank:]]*\$\(COMPILE_CXX_CP_E\)[[:blank:]]*$"#)
         .context("Building regex pattern for C++ compilation")?;
-    let rules_text = fs::read_to_string(rules_path.as_path())
-        .context(format!(r#"Can't read file "{}""#, rules_path.display()))?;
-    if pattern_cxx.is_match(&rules_text) {
-        let rules_text_hacked = pattern_cxx.replace_all(&rules_text, "\t##JCDB## >>:directory:>> $(shell pwd | sed -z 's/\\n//g') >>:command:>> $(COMPILE_CXX_CP) >>:file:>> $<").to_string();
-        fs::write(rules_path.as_path(), &rules_text_hacked).context(format!(
-            r#"Writing to file "{}" failed"#,
-            rules_path.display()
-        ))?;
-        changed_files.push(ChangedFile {
-            file: &rules_path,
-            orig: rules_text,
-            post: rules_text_hacked,
-        });
-    }
-    let common_rules_text = fs::read_to_string(common_rules_path.as_path())?;
-    if pattern_cxx.is_match(&common_rules_text) {
-        let common_rules_text_hacked = pattern_cxx.replace_all(&common_rules_text, "\t##JCDB## >>:directory:>> $(shell pwd | sed -z 's/\\n//g') >>:command:>> $(COMPILE_CXX_CP) >>:file:>> $<").to_string();
-        fs::write(common_rules_path.as_path(), &common_rules_text_hacked).context(format!(
+    let regex_core_rule =
+        Regex::new(CORE_RULE_PATTERN).context("Construct regex for core rule failed")?;
+    let regex_target = Regex::new(r#"\S+"#).context("Construct regex for target failed")?;
+
+    let mut changed_files = Vec::new();
+    for entry in entries.iter() {
+        let text_hacked = if entry.file == lastrules_path {
+            // Hacking for c files
+            let captures = pattern_c
+                .captures(&entry.orig)
+                .context(format!("Failed to capture pattern {}", pattern_c.as_str()))?;
+            let comp_args_c = captures.get(1).unwrap().as_str();
+            pattern_c.replace_all(&entry.orig, format!("\t{} >>:directory:>> $(shell pwd | sed -z 's/\\n//g') >>:command:>> $(CC) {} >>:file:>> $<", INJECTION_MARKER, comp_args_c)).to_string()
+        } else if top_makefile.is_some_and(|x| x == entry.file) {
+            // Hacking for make target when running at project root
+            let captures = regex_core_rule
+                .captures(&entry.orig)
+                .context(format!("Pattern not found: {}", regex_core_rule.as_str(),))?;
+            let targets_renamed =
+                regex_target.replace_all(captures.get(1).unwrap().as_str(), "$0-orig");
+            regex_core_rule
+                .replace(
+                    &entry.orig,
+                    format!(
+                        "$1: make_sub # {}\n\n{}:$2",
+                        INJECTION_MARKER, targets_renamed
+                    ),
+                )
+                .to_string()
+        } else if pattern_cxx.is_match(&entry.orig) {
+            // Hacking for cxx files
+            pattern_cxx.replace_all(&entry.orig, format!("\t{} >>:directory:>> $(shell pwd | sed -z 's/\\n//g') >>:command:>> $(COMPILE_CXX_CP) >>:file:>> $<", INJECTION_MARKER)).to_string()
+        } else {
+            continue;
+        };
+        fs::write(&entry.file, &text_hacked).context(format!(
             r#"Writing to file "{}" failed"#,
-            common_rules_path.display()
+            entry.file.display()
         ))?;
-        changed_files.push(ChangedFile {
-            file: &common_rules_path,
-            orig: common_rules_text,
-            post: common_rules_text_hacked,
-        });
+        changed_files.push(entry.file.clone());
     }
 
-    // Hacking for make target when running at project root
-    if at_proj_root {
-        let regex_core_rule =
-            Regex::new(r#"(?m)^((?:\s*[^:\s]*\s+)*stoneos-image(?:\s+[^:\s]*)*):(.*)$"#)
-                .context("Construct regex for core rule failed")?;
-        let top_makefile_text = fs::read_to_string(top_makefile.as_path())
-            .context(format!("Failed to read {}", top_makefile.display()))?;
-        let captures = regex_core_rule
-            .captures(&top_makefile_text)
-            .context(format!("Pattern not found: {}", regex_core_rule.as_str(),))?;
-        let targets_renamed = Regex::new(r#"\S+"#)
-            .context("Construct regex for target failed")?
-            .replace_all(captures.get(1).unwrap().as_str(), "$0-orig");
-        let top_makefile_text_hacked = regex_core_rule
-            .replace(
-                &top_makefile_text,
-                format!("$1: make_sub\n\n{}:$2", targets_renamed),
-            )
-            .to_string();
-        fs::write(top_makefile.as_path(), &top_makefile_text_hacked)
-            .context(format!("Failed to inject {}", top_makefile.display()))?;
-        changed_files.push(ChangedFile {
-            file: &top_makefile,
-            orig: top_makefile_text,
-            post: top_makefile_text_hacked,
-        });
-    }
-    pb1.set_style(ProgressStyle::with_template(&format!(
-        "[{}/{}] Injected makefiles ({} modified).",
-        step,
-        NSTEPS,
-        changed_files
-            .iter()
-            .map(|x| x.file.file_name().unwrap().display().to_string())
-            .collect::<Vec<String>>()
-            .join(" & ")
-    ))?);
-    pb1.finish();
+    Ok(changed_files)
+}
 
-    // Build the target (pseudoly)
-    step += 1;
-    let mut cmd = Command::new("hsdocker7");
+#[allow(clippy::too_many_arguments)]
+fn build_pseudoly(
+    runner: &Runner,
+    make_directory: &str,
+    make_target: &str,
+    macros: &IndexMap<String, String>,
+    overrides: &[String],
+    buildlog: &Path,
+    expected: Option<usize>,
+    step: usize,
+    nsteps: usize,
+) -> anyhow::Result<()> {
     let command_core = format!(
-        "make -C {} {} -iknBj8 ISBUILDRELEASE=1 NOTBUILDUNIWEBUI=1 HS_BUILD_COVERITY=0{} >{} 2>&1",
+        "make -C {} {} -iknBj8 ISBUILDRELEASE=1 NOTBUILDUNIWEBUI=1 HS_BUILD_COVERITY=0{}{}",
         make_directory,
         make_target,
         if !macros.is_empty() {
@@ -245,9 +675,13 @@ pub(crate) fn gen_compdb_by_builtin(
         } else {
             String::new()
         },
-        BUILDLOG_PATH
+        if !overrides.is_empty() {
+            " ".to_string() + overrides.join(" ").as_str()
+        } else {
+            String::new()
+        },
     );
-    cmd.arg(command_core.as_str());
+    let mut cmd = runner.command(&command_core, &env::current_dir()?, buildlog)?;
     unsafe {
         let mut master_fd: libc::c_int = 0;
         let mut slave_fd: libc::c_int = 0;
@@ -271,78 +705,172 @@ pub(crate) fn gen_compdb_by_builtin(
                 // stdin continues to use master_fd, stdout and stderr use slave_fd
                 libc::dup2(slave_fd, libc::STDOUT_FILENO);
                 libc::dup2(slave_fd, libc::STDERR_FILENO);
-                let mut child = cmd.spawn().context("Failed to execute hsdocker7")?;
-                let status = loop {
-                    if let Some(status) = child
-                        .try_wait()
-                        .context("Error attempting to wait: pseudo building")?
-                    {
-                        break status;
-                    }
-                    thread::sleep(TICK_INTERVAL);
+                // Never return from here, or the forked process would go on restoring the
+                // makefiles along with the parent
+                let code = match cmd.status() {
+                    Ok(status) if status.success() => 0,
+                    _ => 1,
                 };
-                if !status.success() {
-                    bail!("Pseudo building failed ({:?})", status.code());
-                }
                 libc::close(slave_fd);
-                libc::exit(0);
+                libc::_exit(code);
             }
             child_pid => {
                 libc::close(slave_fd);
-                let pb2 = ProgressBar::no_length().with_style(
+                let pb2 = progress_bar::no_length().with_style(
                     ProgressStyle::with_template(&format!(
-                        "[{}/{}] Building pseudoly ({}) {{spinner:.green}} [{{elapsed_precise}}]",
-                        step, NSTEPS, command_core
+                        "[{}/{}] Building pseudoly ({}) {{spinner:.green}} [{{elapsed_precise}}] {{msg}}",
+                        step, nsteps, command_core
                     ))?
                     .tick_chars(TICK_CHARS),
                 );
                 pb2.enable_steady_tick(TICK_INTERVAL);
+                let mut tail = BuildLogTail::new(buildlog, UnitKind::Record, expected);
                 let mut status: libc::c_int = 0;
-                let result = libc::waitpid(child_pid, &mut status, 0);
+                let result = loop {
+                    let result = libc::waitpid(child_pid, &mut status, libc::WNOHANG);
+                    if result == -1
+                        && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+                    {
+                        continue;
+                    }
+                    if result == 0 {
+                        tail.poll();
+                        pb2.set_message(tail.message(pb2.elapsed()));
+                        thread::sleep(TICK_INTERVAL);
+                        continue;
+                    }
+                    break result;
+                };
+                tail.poll();
                 if result == -1 {
                     bail!("Failed to waitpid");
                 }
 
-                if libc::WIFEXITED(status) {
-                    let exit_status = libc::WEXITSTATUS(status);
-                    pb2.set_style(ProgressStyle::with_template(&format!(
-                        "[{}/{}] Building pseudoly ({})...{{msg}}",
-                        step, NSTEPS, command_core
-                    ))?);
-                    if exit_status == 0 {
-                        pb2.finish_with_message("ok");
-                    } else {
-                        pb2.finish_with_message("err");
-                    }
-                } else {
-                    pb2.finish_with_message("err");
+                pb2.set_style(ProgressStyle::with_template(&format!(
+                    "[{}/{}] Building pseudoly ({})...{{msg}}",
+                    step, nsteps, command_core
+                ))?);
+                if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+                    pb2.finish_with_message(format!("ok ({} records)", tail.units));
+                    return Ok(());
                 }
+                pb2.finish_with_message("err");
             }
         }
     }
 
-    // Restore all modified files
-    step += 1;
-    let pb3 = ProgressBar::no_length().with_style(
-        ProgressStyle::with_template(&format!(
-            "[{}/{}] Restoring makefiles {{spinner:.green}}",
-            step, NSTEPS,
-        ))?
+    report_failed_build(buildlog, make_target)?;
+    bail!("Pseudo build failed");
+}
+
+/// Build pseudoly with the recipes injected into the makefiles in place, which are journaled
+/// beforehand and restored afterwards. Takes the first three steps.
+#[allow(clippy::too_many_arguments)]
+fn build_with_injection(
+    runner: &Runner,
+    repoinfo: &RepoInfo,
+    at_proj_root: bool,
+    make_directory: &str,
+    make_target: &str,
+    macros: &IndexMap<String, String>,
+    buildlog: &Path,
+    expected: Option<usize>,
+    nsteps: usize,
+) -> anyhow::Result<()> {
+    let repo_root = PathBuf::from(repoinfo.work_dir());
+    let lastrules_path = repo_root.join("scripts/last-rules.mk");
+    let rules_path = repo_root.join("scripts/rules.mk");
+    let common_rules_path = repo_root.join("scripts/common-rules.mk");
+    let top_makefile = repo_root.join("Makefile");
+    let journal = repo_root.join(JOURNAL_PATH);
+    let mut step: usize = 1;
+
+    let pb1 = progress_bar::no_length().with_style(
+        ProgressStyle::with_template(
+            format!("[{}/{}] Injecting mkfiles {{spinner:.green}}", step, nsteps).as_str(),
+        )?
         .tick_chars(TICK_CHARS),
     );
-    pb3.enable_steady_tick(TICK_INTERVAL);
-    for item in changed_files.iter() {
-        fs::write(item.file, &item.orig)
-            .context(format!("Failed to restore {}", item.file.display()))?;
+    pb1.enable_steady_tick(TICK_INTERVAL);
+    // Styles used between injecting and restoring are made up front, so that no error in
+    // between can skip the restoring
+    let injected_style = ProgressStyle::with_template(&format!(
+        "[{}/{}] Injected makefiles ({{msg}} modified).",
+        step, nsteps
+    ))?;
+    let restoring_style = ProgressStyle::with_template(&format!(
+        "[{}/{}] Restoring makefiles {{spinner:.green}}",
+        step + 2,
+        nsteps,
+    ))?
+    .tick_chars(TICK_CHARS);
+
+    // Journal the originals before touching any of them
+    let mut makefiles = vec![&lastrules_path, &rules_path, &common_rules_path];
+    if at_proj_root {
+        makefiles.push(&top_makefile);
+    }
+    let mut entries = Vec::with_capacity(makefiles.len());
+    for file in makefiles {
+        entries.push(JournalEntry {
+            file: file.clone(),
+            orig: fs::read_to_string(file)
+                .context(format!(r#"Can't read file "{}""#, file.display()))?,
+        });
     }
+    write_journal(&journal, &entries)?;
+    defer_interrupts();
+
+    let injected = inject_makefiles(
+        &entries,
+        &lastrules_path,
+        at_proj_root.then_some(top_makefile.as_path()),
+    );
+    let built = match injected.as_ref() {
+        Ok(changed_files) => {
+            pb1.set_style(injected_style);
+            pb1.finish_with_message(
+                changed_files
+                    .iter()
+                    .map(|x| x.file_name().unwrap().display().to_string())
+                    .collect::<Vec<String>>()
+                    .join(" & "),
+            );
+
+            // Build the target (pseudoly)
+            step += 1;
+            build_pseudoly(
+                runner,
+                make_directory,
+                make_target,
+                macros,
+                &[],
+                buildlog,
+                expected,
+                step,
+                nsteps,
+            )
+        }
+        Err(_) => {
+            pb1.finish_and_clear();
+            Ok(())
+        }
+    };
+
+    // Restore all modified files, whatever happened above
+    step += 1;
+    let pb3 = progress_bar::no_length().with_style(restoring_style);
+    pb3.enable_steady_tick(TICK_INTERVAL);
+    let restored = restore_from_journal(&journal);
+    let interrupted = undefer_interrupts();
+    let restored = restored?;
     pb3.set_style(ProgressStyle::with_template(&format!(
         "[{}/{}] Restored makefiles ({} restored).",
         step,
-        NSTEPS,
-        changed_files
+        nsteps,
+        restored
             .iter()
             .map(|x| x
-                .file
                 .file_name()
                 .expect("Get filename failed")
                 .display()
@@ -351,149 +879,557 @@ pub(crate) fn gen_compdb_by_builtin(
             .join(" & ")
     ))?);
     pb3.finish();
+    injected?;
+    built?;
+    if interrupted {
+        bail!("Interrupted");
+    }
+
+    Ok(())
+}
+
+/// Compose the extra make arguments which inject the recipes without touching any tracked file.
+/// The compile recipes are replaced by command-line variables, which make passes down to every
+/// sub-make through MAKEFLAGS. `$(HS_CC)` only expands to the recipe where it compiles a C file
+/// into an object, and stays `$(CC)` elsewhere, such as in `$(shell ...)` or link recipes.
+///
+/// At project root, the prerequisites of stoneos-image other than make_sub are marked as old
+/// (`-o`) so that only the modules get built. The recipe of stoneos-image gets `MAKE` turned into
+/// `true`, privately so that make_sub keeps it, since lines running `$(MAKE)` are run even under
+/// `-n`.
+fn compose_overlay_args(top_makefile: Option<&Path>) -> anyhow::Result<Vec<String>> {
+    let mut args = vec![
+        utils::shell_quote(&format!(
+            "HS_CC=$(if $(and $(filter %.o,$@),$(filter %.c,$<)),{} >>:directory:>> $(CURDIR) >>:command:>> $(CC),$(CC))",
+            INJECTION_MARKER
+        )),
+        utils::shell_quote(&format!(
+            "COMPILE_CXX_CP_E={} >>:directory:>> $(CURDIR) >>:command:>> $(COMPILE_CXX_CP) >>:file:>> $<",
+            INJECTION_MARKER
+        )),
+    ];
+    if let Some(top_makefile) = top_makefile {
+        let regex_core_rule =
+            Regex::new(CORE_RULE_PATTERN).context("Construct regex for core rule failed")?;
+        let text = fs::read_to_string(top_makefile)
+            .context(format!("Failed to read {}", top_makefile.display()))?;
+        let captures = regex_core_rule
+            .captures(&text)
+            .context(format!("Pattern not found: {}", regex_core_rule.as_str()))?;
+        args.push(utils::shell_quote(&format!(
+            "--eval={}: private MAKE := true",
+            captures.get(1).unwrap().as_str().trim()
+        )));
+        for prerequisite in captures
+            .get(2)
+            .unwrap()
+            .as_str()
+            .split_whitespace()
+            .filter(|x| *x != "make_sub")
+        {
+            args.push(format!("-o {}", utils::shell_quote(prerequisite)));
+        }
+    }
+    Ok(args)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn gen_compdb_by_builtin(
+    runner: &Runner,
+    repoinfo: &RepoInfo,
+    make_directory: &str,
+    make_target: &str,
+    macros: &IndexMap<String, String>,
+    overlay: bool,
+    expected: Option<usize>,
+    keep_log: bool,
+) -> anyhow::Result<OutputLock> {
+    let nsteps: usize = if overlay { 3 } else { 5 };
+
+    // Invoke svn firstly to check whether we are in a working copy
+    let repo_root = PathBuf::from(repoinfo.work_dir());
+    let at_proj_root = env::current_dir()? == repo_root;
+
+    let lastrules_path = repo_root.join("scripts/last-rules.mk");
+    let rules_path = repo_root.join("scripts/rules.mk");
+    let common_rules_path = repo_root.join("scripts/common-rules.mk");
+    let top_makefile = repo_root.join("Makefile");
+    let journal = repo_root.join(JOURNAL_PATH);
+
+    // Held until the makefiles are restored, or shared by overlay runs until built, so that a
+    // journal found here is left by a run which did not finish
+    let injection_lock = lock_injection(&repo_root, true, overlay)?;
+    if journal.is_file() {
+        bail!(
+            r#"Makefiles injected by an unfinished run have not been restored yet, run "rua compdb recover" first"#
+        );
+    }
+    if !lastrules_path.is_file() {
+        bail!(r#"File not found: "{}""#, lastrules_path.display());
+    }
+    if !rules_path.is_file() {
+        bail!(r#"File not found: "{}""#, rules_path.display());
+    }
+    if !common_rules_path.is_file() {
+        bail!(r#"File not found: "{}""#, common_rules_path.display());
+    }
+    if at_proj_root && !top_makefile.is_file() {
+        bail!(r#"File not found: "{}""#, top_makefile.display());
+    }
+
+    let buildlog = run_buildlog(&repo_root);
+    let mut step = if overlay {
+        let overrides = compose_overlay_args(at_proj_root.then_some(top_makefile.as_path()))?;
+        build_pseudoly(
+            runner,
+            make_directory,
+            make_target,
+            macros,
+            &overrides,
+            &buildlog,
+            expected,
+            1,
+            nsteps,
+        )?;
+        1
+    } else {
+        build_with_injection(
+            runner,
+            repoinfo,
+            at_proj_root,
+            make_directory,
+            make_target,
+            macros,
+            &buildlog,
+            expected,
+            nsteps,
+        )?;
+        3
+    };
+    drop(injection_lock);
+    let output_lock = lock_output(repoinfo)?;
 
     // Parse the build log
     step += 1;
-    let pb4 = ProgressBar::no_length().with_style(
+    let pb4 = progress_bar::no_length().with_style(
         ProgressStyle::with_template(&format!(
             "[{}/{}] Parsing buildlog {{spinner:.green}}",
-            step, NSTEPS
+            step, nsteps
         ))?
         .tick_chars(TICK_CHARS),
     );
     pb4.enable_steady_tick(TICK_INTERVAL);
-    let output_str = fs::read_to_string(BUILDLOG_PATH)?;
-    fs::remove_file(BUILDLOG_PATH)?;
+    let output_str = fs::read_to_string(&buildlog)?;
+    // The file part is absent for C recipes injected in overlay mode, where the source file is
+    // the last argument of the command
     let pattern_hackrule = Regex::new(
-        r#"(?m)^##JCDB##[[:blank:]]+>>:directory:>>[[:blank:]]+([^>]+?)[[:blank:]]+>>:command:>>[[:blank:]]+([^>]+?)[[:blank:]]+>>:file:>>[[:blank:]]+(.+)[[:blank:]]*$"#,
+        r#"(?m)^##JCDB##[[:blank:]]+>>:directory:>>[[:blank:]]+([^>]+?)[[:blank:]]+>>:command:>>[[:blank:]]+([^>]+?)(?:[[:blank:]]+>>:file:>>[[:blank:]]+(.+?))?[[:blank:]]*$"#,
     ).context("Failed to build pattern for hackrules")?;
     let mut records: Vec<CompdbRecord> = Vec::new();
-    for (_, [dirc, comm, file]) in pattern_hackrule
-        .captures_iter(&output_str)
-        .map(|c| c.extract())
-    {
+    for captures in pattern_hackrule.captures_iter(&output_str) {
+        let dirc = captures.get(1).unwrap().as_str();
+        let comm = captures.get(2).unwrap().as_str();
+        let file = match captures.get(3) {
+            Some(v) => v.as_str(),
+            None => match comm.rsplit_once(char::is_whitespace) {
+                Some((_, v)) if comm.contains(" -c ") && is_source_file(v) => v,
+                _ => continue,
+            },
+        };
         records.push(CompdbRecord {
             directory: dirc.to_string(),
-            command: comm.to_string(),
+            compile_command: CommandOrArguments::Command {
+                command: comm.to_string(),
+            },
             file: Path::new(&dirc).join(file).to_string_lossy().to_string(),
+            output: None,
         });
     }
     pb4.set_style(ProgressStyle::with_template(&format!(
         "[{}/{}] Parsed buildlog.",
-        step, NSTEPS
+        step, nsteps
     ))?);
     pb4.finish();
+    dispose_buildlog(&buildlog, make_target, keep_log)?;
 
     // Generate JCDB
     step += 1;
-    let pb5 = ProgressBar::no_length().with_style(
+    let pb5 = progress_bar::no_length().with_style(
         ProgressStyle::with_template(&format!(
             "[{}/{}] Generating compilation database {{spinner:.green}}",
-            step, NSTEPS
+            step, nsteps
         ))?
         .tick_chars(TICK_CHARS),
     );
     pb5.enable_steady_tick(TICK_INTERVAL);
-    let mut jcdb = json!([]);
-    for item in records.iter() {
-        jcdb.as_array_mut().unwrap().push(json!({
-            "directory": item.directory,
-            "command": item.command,
-            "file": item.file,
-        }));
-    }
-    fs::write(COMPDB_FILE, serde_json::to_string_pretty(&jcdb)?)?;
+    fs::write(COMPDB_FILE, serde_json::to_string_pretty(&records)?)?;
     pb5.set_style(ProgressStyle::with_template(&format!(
         "[{}/{}] Generated compilation database.",
-        step, NSTEPS
+        step, nsteps
     ))?);
     pb5.finish();
 
+    Ok(output_lock)
+}
+
+/// Restore makefiles left injected by an aborted built-in run from the journal, and detect
+/// leftover injection markers which can not be restored automatically.
+pub(crate) fn recover_makefiles(repo_info: &RepoInfo) -> anyhow::Result<()> {
+    let repo_root = PathBuf::from(repo_info.work_dir());
+    let journal = repo_root.join(JOURNAL_PATH);
+    let _lock = lock_injection(&repo_root, false, false)?;
+    let journaled = journal.is_file();
+    if journaled {
+        status_line::begin("Restoring makefiles from journal...")?;
+        let restored = restore_from_journal(&journal)?;
+        status_line::end("Restoring makefiles from journal...ok");
+        for item in restored.iter() {
+            eprintln!("  {}", item.display());
+        }
+    }
+
+    let leftovers = INJECTABLE_MAKEFILES
+        .iter()
+        .map(|x| repo_root.join(x))
+        .filter(|x| fs::read_to_string(x).is_ok_and(|text| text.contains(INJECTION_MARKER)))
+        .collect::<Vec<PathBuf>>();
+    if !leftovers.is_empty() {
+        bail!(
+            "Injection markers found but no journal to restore from, revert these files by svn/git (make sure you have backed up the changes you made):\n  {}",
+            leftovers
+                .iter()
+                .map(|x| x.display().to_string())
+                .collect::<Vec<String>>()
+                .join("\n  ")
+        );
+    }
+    if !journaled {
+        eprintln!("No injected makefile left.");
+    }
+
     Ok(())
 }
 
 pub(crate) fn gen_compdb_by_intercept_build<T: AsRef<Path>>(
-    _svninfo: &RepoInfo,
+    runner: &Runner,
+    svninfo: &RepoInfo,
     intercept_build_path: T,
     make_directory: &str,
     make_target: &str,
-) -> anyhow::Result<()> {
-    let pb = ProgressBar::no_length().with_style(
+    expected: Option<usize>,
+    keep_log: bool,
+) -> anyhow::Result<OutputLock> {
+    // The compilation database is written while building
+    let output_lock = lock_output(svninfo)?;
+    let buildlog = run_buildlog(Path::new(svninfo.work_dir()));
+    let pb = progress_bar::no_length().with_style(
         ProgressStyle::with_template(
-            "Generating compilation database by intercept-build {spinner:.green} [{elapsed_precise}]",
+            "Generating compilation database by intercept-build {spinner:.green} [{elapsed_precise}] {msg}",
         )?
         .tick_chars(TICK_CHARS),
     );
     pb.enable_steady_tick(TICK_INTERVAL);
-    let mut command = Command::new("hsdocker7");
-    let command_with_args = command.arg(format!(
-        "{} make -C {} -j8 {} >{} 2>&1",
-        intercept_build_path.as_ref().to_str().unwrap(),
-        make_directory,
-        make_target,
-        BUILDLOG_PATH
-    ));
-    let mut child_proc = command_with_args
-        .spawn()
-        .context("Error spawning child process")?;
-    let status = loop {
-        if let Some(v) = child_proc.try_wait()? {
-            break v;
-        }
-        thread::sleep(TICK_INTERVAL);
-    };
-    if !status.success() {
-        bail!("Intercept-build running failed ({:?})", status.code());
-    }
-    fs::remove_file(BUILDLOG_PATH)?;
+    let mut command = runner.command(
+        &format!(
+            "{} make -C {} -j8 {}",
+            intercept_build_path.as_ref().to_str().unwrap(),
+            make_directory,
+            make_target,
+        ),
+        &env::current_dir()?,
+        &buildlog,
+    )?;
+    let mut child_proc = command.spawn().context("Error spawning child process")?;
+    let mut tail = BuildLogTail::new(&buildlog, UnitKind::Invocation, expected);
+    let status = wait_following_log(&mut child_proc, &pb, &mut tail)?;
     pb.disable_steady_tick();
     pb.set_style(ProgressStyle::with_template(
         "Generating compilation database by intercept-build...{msg}",
     )?);
+    if !status.success() {
+        pb.finish_with_message("err");
+        report_failed_build(&buildlog, make_target)?;
+        bail!("Intercept-build running failed ({:?})", status.code());
+    }
     pb.finish_with_message("ok");
-    Ok(())
+    dispose_buildlog(&buildlog, make_target, keep_log)?;
+    Ok(output_lock)
 }
 
 pub(crate) fn gen_compdb_by_bear<T: AsRef<Path>>(
-    _repo_info: &RepoInfo,
+    runner: &Runner,
+    repo_info: &RepoInfo,
     bear_path: T,
     make_directory: &str,
     make_target: &str,
-) -> anyhow::Result<()> {
-    let pb = ProgressBar::no_length().with_style(
+    expected: Option<usize>,
+    keep_log: bool,
+) -> anyhow::Result<OutputLock> {
+    // The compilation database is written while building
+    let output_lock = lock_output(repo_info)?;
+    let buildlog = run_buildlog(Path::new(repo_info.work_dir()));
+    let pb = progress_bar::no_length().with_style(
         ProgressStyle::with_template(
-            "Generating compilation database by bear {spinner:.green} [{elapsed_precise}]",
+            "Generating compilation database by bear {spinner:.green} [{elapsed_precise}] {msg}",
         )?
         .tick_chars(TICK_CHARS),
     );
     pb.enable_steady_tick(TICK_INTERVAL);
-    let mut command = Command::new("hsdocker7");
-    let command_with_args = command.arg(format!(
-        "{} -- make -C {} -j8 {} >{} 2>&1",
-        bear_path.as_ref().to_str().unwrap(),
-        make_directory,
-        make_target,
-        BUILDLOG_PATH
-    ));
-    let mut child_proc = command_with_args
-        .spawn()
-        .context("Spawn child process failed")?;
-    let status = loop {
-        if let Some(v) = child_proc.try_wait()? {
-            break v;
-        }
-        thread::sleep(TICK_INTERVAL);
-    };
+    let mut command = runner.command(
+        &format!(
+            "{} -- make -C {} -j8 {}",
+            bear_path.as_ref().to_str().unwrap(),
+            make_directory,
+            make_target,
+        ),
+        &env::current_dir()?,
+        &buildlog,
+    )?;
+    let mut child_proc = command.spawn().context("Spawn child process failed")?;
+    let mut tail = BuildLogTail::new(&buildlog, UnitKind::Invocation, expected);
+    let status = wait_following_log(&mut child_proc, &pb, &mut tail)?;
+    pb.disable_steady_tick();
+    pb.set_style(ProgressStyle::with_template(
+        "Generating compilation database by bear...{msg}",
+    )?);
     if !status.success() {
+        pb.finish_with_message("err");
+        report_failed_build(&buildlog, make_target)?;
         bail!("Bear run failed ({:?})", status.code());
     }
-    fs::remove_file(BUILDLOG_PATH)?;
+    pb.finish_with_message("ok");
+    dispose_buildlog(&buildlog, make_target, keep_log)?;
+    Ok(output_lock)
+}
+
+const SHIM_DIR: &str = ".rua/shims";
+const SHIM_LOG_PATH: &str = ".rua/shims.log";
+const SHIM_DIR_ENV: &str = "RUA_COMPDB_SHIM_DIR";
+const SHIM_LOG_ENV: &str = "RUA_COMPDB_SHIM_LOG";
+
+/// Compiler invocation recorded by a compiler shim, one JSON object per line in the shim log.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ShimRecord {
+    directory: String,
+    arguments: Vec<String>,
+}
+
+/// Tell whether the current process was launched through one of the compiler shims rather than
+/// as rua itself. The shims are symlinks to the rua binary, so argv[0] carries the compiler name.
+pub(crate) fn is_compiler_shim() -> bool {
+    if env::var_os(SHIM_LOG_ENV).is_none() {
+        return false;
+    }
+    let argv0 = env::args_os().next().map(PathBuf::from);
+    let exe = env::current_exe().ok();
+    match (argv0, exe) {
+        (Some(argv0), Some(exe)) => argv0.file_name() != exe.file_name(),
+        _ => false,
+    }
+}
+
+/// Act as the compiler: record argv and working directory to the shim log, then exec the real
+/// compiler found in PATH behind the shim dir. Only returns if the exec fails.
+pub(crate) fn run_compiler_shim() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args_os()
+        .map(|x| x.to_string_lossy().to_string())
+        .collect();
+    let name = Path::new(&args[0])
+        .file_name()
+        .context("Invalid compiler name")?
+        .to_os_string();
+
+    // Recording must never break the build, so errors are ignored here
+    if let Some(log_path) = env::var_os(SHIM_LOG_ENV)
+        && let Ok(directory) = env::current_dir()
+    {
+        let record = ShimRecord {
+            directory: directory.to_string_lossy().to_string(),
+            arguments: args.clone(),
+        };
+        if let Ok(mut line) = serde_json::to_string(&record) {
+            line.push('\n');
+            if let Ok(mut file) = fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(log_path)
+            {
+                let _ = file.write_all(line.as_bytes());
+            }
+        }
+    }
+
+    let shim_dir = env::var_os(SHIM_DIR_ENV).and_then(|x| fs::canonicalize(x).ok());
+    let path = env::var_os("PATH").context("PATH not set")?;
+    let real_compiler = env::split_paths(&path)
+        .filter(|x| shim_dir.is_none() || fs::canonicalize(x).ok() != shim_dir)
+        .map(|x| x.join(&name))
+        .find(|x| {
+            x.metadata()
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+        .context(format!(
+            "Compiler {} not found in PATH",
+            name.to_string_lossy()
+        ))?;
+    // The real path is passed as argv[0] since gcc locates its own components by it
+    let err = Command::new(&real_compiler).args(&args[1..]).exec();
+    Err(err).context(format!("Failed to execute {}", real_compiler.display()))
+}
+
+/// Tell whether the argument looks like a source file to be compiled.
+fn is_source_file(arg: &str) -> bool {
+    const SOURCE_EXTENSIONS: [&str; 8] = ["c", "cc", "cpp", "cxx", "c++", "C", "s", "S"];
+    Path::new(arg)
+        .extension()
+        .is_some_and(|x| SOURCE_EXTENSIONS.iter().any(|y| x == *y))
+}
+
+/// Turn the compiler invocations recorded by the shims into compdb records. Only invocations
+/// that compile (`-c`) produce records, one per source file passed in.
+fn parse_shim_log(log: &str) -> anyhow::Result<Vec<CompdbRecord>> {
+    let mut records = Vec::new();
+    for line in log.lines().filter(|x| !x.trim().is_empty()) {
+        let invocation: ShimRecord =
+            serde_json::from_str(line).context(format!("Failed to parse shim record: {}", line))?;
+        if !invocation.arguments.iter().any(|x| x == "-c") {
+            continue;
+        }
+        let command = invocation
+            .arguments
+            .iter()
+            .map(|x| utils::shell_quote(x))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let mut prev_is_output = false;
+        for arg in invocation.arguments.iter().skip(1) {
+            if prev_is_output {
+                prev_is_output = false;
+                continue;
+            }
+            if arg == "-o" {
+                prev_is_output = true;
+                continue;
+            }
+            if arg.starts_with('-') || !is_source_file(arg) {
+                continue;
+            }
+            records.push(CompdbRecord {
+                directory: invocation.directory.clone(),
+                compile_command: CommandOrArguments::Command {
+                    command: command.clone(),
+                },
+                file: normalize_path(Path::new(&invocation.directory).join(arg))
+                    .to_string_lossy()
+                    .to_string(),
+                output: None,
+            });
+        }
+    }
+    Ok(records)
+}
+
+pub(crate) fn gen_compdb_by_wrapper(
+    runner: &Runner,
+    repo_info: &RepoInfo,
+    shims: &[String],
+    make_directory: &str,
+    make_target: &str,
+    expected: Option<usize>,
+    keep_log: bool,
+) -> anyhow::Result<OutputLock> {
+    // The compilation database is written while building
+    let output_lock = lock_output(repo_info)?;
+    let buildlog = run_buildlog(Path::new(repo_info.work_dir()));
+    let pb = progress_bar::no_length().with_style(
+        ProgressStyle::with_template(
+            "Generating compilation database by compiler shims {spinner:.green} [{elapsed_precise}] {msg}",
+        )?
+        .tick_chars(TICK_CHARS),
+    );
+    pb.enable_steady_tick(TICK_INTERVAL);
+
+    // Set up the shims, all of which link to the rua binary itself
+    let rua_path = env::current_exe().context("Failed to locate the rua binary")?;
+    let shim_dir = Path::new(repo_info.work_dir()).join(SHIM_DIR);
+    let shim_log = Path::new(repo_info.work_dir()).join(SHIM_LOG_PATH);
+    if shim_dir.exists() {
+        fs::remove_dir_all(&shim_dir)
+            .context(format!("Failed to remove {}", shim_dir.display()))?;
+    }
+    fs::create_dir_all(&shim_dir).context(format!("Failed to create {}", shim_dir.display()))?;
+    for name in shims.iter() {
+        std::os::unix::fs::symlink(&rua_path, shim_dir.join(name))
+            .context(format!("Failed to create shim for {}", name))?;
+    }
+    fs::write(&shim_log, "").context(format!("Failed to create {}", shim_log.display()))?;
+
+    // The build runs by the runner, which may not see the rua binary the shims link to
+    if let Some(name) = shims.first()
+        && run_probe(
+            runner,
+            Path::new(repo_info.work_dir()),
+            &env::current_dir()?,
+            &format!(
+                "test -x {}",
+                utils::shell_quote(&shim_dir.join(name).to_string_lossy())
+            ),
+        )
+        .is_err()
+    {
+        fs::remove_dir_all(&shim_dir)?;
+        fs::remove_file(&shim_log)?;
+        pb.finish_and_clear();
+        bail!(
+            "The compiler shims link to {}, which can't be run by the runner. Make rua available at the same path inside the build container, or set `runner = \"direct\"`",
+            rua_path.display()
+        );
+    }
+
+    let mut command = runner.command(
+        &format!(
+            "PATH={0}:$PATH {1}={0} {2}={3} make -C {4} -j8 {5}",
+            shim_dir.display(),
+            SHIM_DIR_ENV,
+            SHIM_LOG_ENV,
+            shim_log.display(),
+            make_directory,
+            make_target,
+        ),
+        &env::current_dir()?,
+        &buildlog,
+    )?;
+    let mut child_proc = command.spawn().context("Spawn child process failed")?;
+    let mut tail = BuildLogTail::new(&buildlog, UnitKind::Invocation, expected);
+    let status = wait_following_log(&mut child_proc, &pb, &mut tail)?;
+    fs::remove_dir_all(&shim_dir).context(format!("Failed to remove {}", shim_dir.display()))?;
     pb.disable_steady_tick();
     pb.set_style(ProgressStyle::with_template(
-        "Generating compilation database by bear...{msg}",
+        "Generating compilation database by compiler shims...{msg}",
     )?);
-    pb.finish_with_message("ok");
-    Ok(())
+    if !status.success() {
+        fs::remove_file(&shim_log)?;
+        pb.finish_with_message("err");
+        report_failed_build(&buildlog, make_target)?;
+        bail!("Building with compiler shims failed ({:?})", status.code());
+    }
+
+    let log =
+        fs::read_to_string(&shim_log).context(format!("Failed to read {}", shim_log.display()))?;
+    fs::remove_file(&shim_log)?;
+    let records = parse_shim_log(&log)?;
+    fs::write(COMPDB_FILE, serde_json::to_string_pretty(&records)?)?;
+    pb.finish_with_message(format!("ok ({} entries)", records.len()));
+    // Compilers called by absolute path never go through the shims on PATH
+    if tail.units > records.len() {
+        eprintln!(
+            "WARNING: {} of {} compiler invocations in the build log bypassed the shims, as compilers called by absolute path (e.g. HS_CC=/path/to/gcc) are not recorded",
+            tail.units - records.len(),
+            tail.units
+        );
+    }
+    dispose_buildlog(&buildlog, make_target, keep_log)?;
+    Ok(output_lock)
 }
 
 pub(crate) fn gen_compdb(
@@ -501,23 +1437,33 @@ pub(crate) fn gen_compdb(
     make_directory: &str,
     make_target: &str,
     options: CompdbOptions,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<OutputLock> {
     let engine = options.engine.unwrap_or(CompdbEngine::BuiltIn);
 
-    match engine {
-        CompdbEngine::BuiltIn => {
-            gen_compdb_by_builtin(repo_info, make_directory, make_target, &options.defines)
-        }
+    let output_lock = match engine {
+        CompdbEngine::BuiltIn => gen_compdb_by_builtin(
+            &options.runner,
+            repo_info,
+            make_directory,
+            make_target,
+            &options.defines,
+            options.overlay,
+            options.expected_entries,
+            options.keep_log,
+        ),
         CompdbEngine::InterceptBuild => {
             let intercept_build_path = options
                 .intercept_build_path
                 .as_deref()
                 .unwrap_or(Path::new(DEFAULT_INTERCEPT_BUILD));
             gen_compdb_by_intercept_build(
+                &options.runner,
                 repo_info,
                 intercept_build_path,
                 make_directory,
                 make_target,
+                options.expected_entries,
+                options.keep_log,
             )
         }
         CompdbEngine::Bear => {
@@ -525,11 +1471,123 @@ pub(crate) fn gen_compdb(
                 .bear_path
                 .as_deref()
                 .unwrap_or(Path::new(DEFAULT_BEAR));
-            gen_compdb_by_bear(repo_info, bear_path, make_directory, make_target)
+            gen_compdb_by_bear(
+                &options.runner,
+                repo_info,
+                bear_path,
+                make_directory,
+                make_target,
+                options.expected_entries,
+                options.keep_log,
+            )
         }
+        CompdbEngine::Wrapper => gen_compdb_by_wrapper(
+            &options.runner,
+            repo_info,
+            &options.shims,
+            make_directory,
+            make_target,
+            options.expected_entries,
+            options.keep_log,
+        ),
     }?;
 
-    merge_compdb(options.to_merge)
+    if !options.to_merge.is_empty() {
+        status_line::begin("Merging compilation databases...")?;
+        let summary = merge_compdb(options.to_merge, options.merge_policy)?;
+        status_line::end(format!("Merging compilation databases...ok ({})", summary));
+    }
+
+    if let Some(mode) = options.system_includes {
+        status_line::begin("Discovering system includes of the toolchains...")?;
+        let discovered = add_system_includes(
+            mode,
+            &options.path_map,
+            &options.runner,
+            Path::new(repo_info.work_dir()),
+        )?;
+        status_line::end(format!(
+            "Discovering system includes of the toolchains...ok ({} found)",
+            discovered
+        ));
+    }
+
+    // The dependency files name the headers by the paths of the build, so the headers are
+    // synthesized before mapping the paths
+    if options.headers {
+        status_line::begin("Synthesizing entries for headers...")?;
+        let added = synthesize_header_entries()?;
+        status_line::end(format!(
+            "Synthesizing entries for headers...ok ({} added)",
+            added
+        ));
+    }
+
+    if !options.path_map.is_empty() {
+        remap_compdb(&options.path_map)?;
+    }
+
+    if options.arguments {
+        tokenize_compdb()?;
+    }
+    Ok(output_lock)
+}
+
+/// Regenerate the compilation database under a subdirectory only and splice the result into the
+/// one in the current directory, replacing exactly the entries for files under that subdirectory.
+/// A compilation database already present in the subdirectory is kept intact.
+///
+/// Returns the number of entries removed and added, along with the output lock to hold until
+/// the result is archived.
+pub(crate) fn update_compdb(
+    repo_info: &RepoInfo,
+    subdir: &Path,
+    make_target: &str,
+    options: CompdbOptions,
+) -> anyhow::Result<(usize, usize, OutputLock)> {
+    let compdb = Path::new(COMPDB_FILE);
+    if !compdb.is_file() {
+        bail!("{} not found, generate one first", compdb.display());
+    }
+    let top_dir = env::current_dir()?;
+    let sub_dir = normalize_path(top_dir.join(subdir));
+    if !sub_dir.is_dir() {
+        bail!("Directory not found: {}", subdir.display());
+    }
+    let sub_compdb = sub_dir.join(COMPDB_FILE);
+    let sub_compdb_orig = fs::read(&sub_compdb).ok();
+
+    env::set_current_dir(&sub_dir).context(format!("Failed to enter {}", sub_dir.display()))?;
+    let generated = gen_compdb(repo_info, ".", make_target, options);
+    env::set_current_dir(&top_dir).context(format!("Failed to enter {}", top_dir.display()))?;
+    let generated = generated.and_then(|x| Ok((read_compdb(&sub_compdb)?, x)));
+    match sub_compdb_orig {
+        Some(v) => fs::write(&sub_compdb, v)?,
+        None => fs::remove_file(&sub_compdb).unwrap_or(()),
+    }
+    let is_under = |x: &CompdbRecord| {
+        normalize_path(Path::new(&x.directory).join(&x.file)).starts_with(&sub_dir)
+    };
+    let (records, output_lock) = generated?;
+    let mut fresh: Vec<CompdbRecord> = records.into_iter().filter(is_under).collect();
+
+    // Put the fresh entries where the stale ones were
+    let mut spliced = Vec::new();
+    let mut removed = 0;
+    let added = fresh.len();
+    for record in read_compdb(compdb)? {
+        if !is_under(&record) {
+            spliced.push(record);
+            continue;
+        }
+        if removed == 0 {
+            spliced.append(&mut fresh);
+        }
+        removed += 1;
+    }
+    spliced.append(&mut fresh);
+    fs::write(compdb, serde_json::to_string_pretty(&spliced)?)?;
+    Ok((removed, added, output_lock))
 }
 
 #[allow(unused)]
@@ -542,9 +1600,25 @@ pub(crate) struct CompdbStoreItem {
     timestamp: i64,
     compdb: Vec<u8>,
     remark: Option<String>,
+    entries: i64,
+    size: i64,
+    compressed: i64,
 }
 
-pub(crate) fn create_tables(conn: &Connection) -> anyhow::Result<()> {
+/// Store of rua 1.x, living next to the current store
+const LEGACY_STORE: &str = "compdbs.db3";
+
+/// Schema migrations of the store, indexed by the schema version they bring the store from. The
+/// schema version is kept in `PRAGMA user_version`, append new migrations to the end only.
+const MIGRATIONS: [fn(&Connection, &Path) -> anyhow::Result<()>; 4] = [
+    migrate_create_tables,
+    migrate_import_legacy,
+    migrate_dedup_blobs,
+    migrate_compressed_size,
+];
+
+/// Create the tables. Stores of rua 2.x predate the versioning and have them already.
+fn migrate_create_tables(conn: &Connection, _store: &Path) -> anyhow::Result<()> {
     // Note that the two generation fields should update independently
     conn.execute("CREATE TABLE IF NOT EXISTS compdbs (generation INTEGER PRIMARY KEY AUTOINCREMENT, branch TEXT NOT NULL, revision TEXT NOT NULL, target TEXT NOT NULL, timestamp INTEGER NOT NULL, compdb BLOB NOT NULL, remark TEXT)", ())?;
     conn.execute(
@@ -554,112 +1628,560 @@ pub(crate) fn create_tables(conn: &Connection) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn add_compdb(
-    conn: &Connection,
-    branch: &str,
-    commit_id: &str,
-    target: &str,
-    compdb: &[u8],
-) -> anyhow::Result<usize> {
-    let timestamp = chrono::Utc::now().timestamp();
-    let rows = conn.execute("INSERT INTO compdbs (branch, revision, target, timestamp, compdb) VALUES (?1, ?2, ?3, ?4, ?5)", params![
-        branch, commit_id, target, timestamp, compdb
-    ])?;
-    Ok(rows)
+/// Import the generations of the rua 1.x store if any, unless the store has been populated
+/// already, which is the case for stores migrated by hand
+fn migrate_import_legacy(conn: &Connection, store: &Path) -> anyhow::Result<()> {
+    let legacy = store.with_file_name(LEGACY_STORE);
+    if !legacy.is_file() {
+        return Ok(());
+    }
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM compdbs", [], |row| row.get(0))?;
+    if count != 0 {
+        return Ok(());
+    }
+    let source = Connection::open_with_flags(&legacy, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .context(format!("Failed to open {}", legacy.display()))?;
+    let mut stmt = source
+        .prepare("SELECT branch, revision, target, timestamp, compdb, remark FROM compdbs ORDER BY generation")
+        .context(format!("Failed to read {}", legacy.display()))?;
+    let mut rows = stmt.query([])?;
+    let mut imported = 0;
+    while let Some(row) = rows.next()? {
+        conn.execute(
+            "INSERT INTO compdbs (branch, revision, target, timestamp, compdb, remark) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, Vec<u8>>(4)?,
+                row.get::<_, Option<String>>(5)?
+            ],
+        )?;
+        imported += 1;
+    }
+    eprintln!(
+        "Imported {} generations from {}",
+        imported,
+        legacy.display()
+    );
+    Ok(())
 }
 
-const STYLE_BOLD: Style = Style::new().bold();
-const STYLE_YELLOW: Style = Style::new().fg_color(Some(Color::Ansi256(Ansi256Color(3))));
+/// Move the compdb blobs of all generations into the content-addressed tables
+fn migrate_dedup_blobs(conn: &Connection, _store: &Path) -> anyhow::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE packs (id INTEGER PRIMARY KEY AUTOINCREMENT, data BLOB NOT NULL);
+        CREATE TABLE records (id INTEGER PRIMARY KEY AUTOINCREMENT, hash TEXT NOT NULL UNIQUE, pack INTEGER NOT NULL, idx INTEGER NOT NULL);
+        CREATE TABLE manifests (digest TEXT PRIMARY KEY, records BLOB NOT NULL, entries INTEGER NOT NULL, size INTEGER NOT NULL);
+        CREATE TABLE compdbs_new (generation INTEGER PRIMARY KEY AUTOINCREMENT, branch TEXT NOT NULL, revision TEXT NOT NULL, target TEXT NOT NULL, timestamp INTEGER NOT NULL, digest TEXT NOT NULL, remark TEXT);",
+    )?;
+    let seq: Option<i64> = conn
+        .query_row(
+            "SELECT seq FROM sqlite_sequence WHERE name='compdbs'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    {
+        let mut stmt = conn.prepare("SELECT generation, branch, revision, target, timestamp, compdb, remark FROM compdbs ORDER BY generation")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let generation: i64 = row.get(0)?;
+            let compressed: Vec<u8> = row.get(5)?;
+            let content = decode_all(&compressed[..])
+                .context(format!("Failed to decompress generation {}", generation))?;
+            let digest = store_compdb(conn, &content)
+                .context(format!("Failed to store generation {}", generation))?;
+            conn.execute(
+                "INSERT INTO compdbs_new (generation, branch, revision, target, timestamp, digest, remark) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    generation,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, i64>(4)?,
+                    digest,
+                    row.get::<_, Option<String>>(6)?
+                ],
+            )?;
+        }
+    }
+    conn.execute_batch(
+        "DROP TABLE compdbs;
+        ALTER TABLE compdbs_new RENAME TO compdbs;",
+    )?;
+    // Keep generation ids of deleted generations from being reused
+    if let Some(seq) = seq {
+        conn.execute(
+            "UPDATE sqlite_sequence SET seq=MAX(seq, ?1) WHERE name='compdbs'",
+            [seq],
+        )?;
+        conn.execute(
+            "INSERT INTO sqlite_sequence (name, seq) SELECT 'compdbs', ?1 WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name='compdbs')",
+            [seq],
+        )?;
+    }
+    Ok(())
+}
 
-pub(crate) struct TableColumn<T>
-where
-    T: ToString,
-{
-    header: String,
-    series: Vec<T>,
+pub(crate) fn get_schema_version(conn: &Connection) -> anyhow::Result<usize> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(version as usize)
 }
 
-impl<T: ToString> TableColumn<T> {
-    pub(crate) fn display_width_header(&self) -> usize {
-        self.header.chars().count()
+/// Record how large each compilation database would be when compressed standalone
+fn migrate_compressed_size(conn: &Connection, _store: &Path) -> anyhow::Result<()> {
+    conn.execute(
+        "ALTER TABLE manifests ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0",
+        (),
+    )?;
+    let digests = conn
+        .prepare("SELECT digest FROM manifests")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    for digest in digests.iter() {
+        let entries = load_compdb(conn, digest)?;
+        conn.execute(
+            "UPDATE manifests SET compressed=?1 WHERE digest=?2",
+            params![compressed_size(&entries)?, digest],
+        )?;
     }
+    Ok(())
+}
 
-    pub(crate) fn display_width_series(&self) -> usize {
-        self.series
-            .iter()
-            .fold(0, |r, x| cmp::max(r, x.to_string().chars().count()))
-    }
+/// How long to wait for other runs holding the store locked before giving up
+const STORE_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
 
-    pub(crate) fn display_width(&self) -> usize {
-        cmp::max(self.display_width_header(), self.display_width_series())
+/// Open the store, waiting for the other runs writing to it instead of failing at once
+pub(crate) fn open_store<P: AsRef<Path>>(store: P) -> anyhow::Result<Connection> {
+    let conn = Connection::open(store.as_ref())
+        .context(format!("Failed to open {}", store.as_ref().display()))?;
+    conn.busy_timeout(STORE_BUSY_TIMEOUT)?;
+    Ok(conn)
+}
+
+/// Bring the store to the latest schema version, each migration runs in its own transaction
+pub(crate) fn migrate_store(conn: &mut Connection, store: &Path) -> anyhow::Result<()> {
+    loop {
+        // Another run may be migrating at the same time, so the version is read again under the
+        // write lock before each step
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let version = get_schema_version(&tx)?;
+        if version > MIGRATIONS.len() {
+            bail!(
+                "The store has schema version {} which is newer than this rua supports ({}), please upgrade rua",
+                version,
+                MIGRATIONS.len()
+            );
+        }
+        let Some(migrate) = MIGRATIONS.get(version) else {
+            return Ok(());
+        };
+        migrate(&tx, store).context(format!(
+            "Failed to migrate the store to version {}",
+            version + 1
+        ))?;
+        tx.pragma_update(None, "user_version", (version + 1) as i64)?;
+        tx.commit()?;
     }
 }
 
-pub(crate) struct Table {
-    col_generation: TableColumn<i64>,
-    col_branch: TableColumn<String>,
-    col_commit: TableColumn<String>,
-    col_target: TableColumn<String>,
-    col_date: TableColumn<String>,
-    col_remark: TableColumn<String>,
-    indicator: String,
-    num_rows: usize,
+/// Print the schema version, generation count and size of the store
+pub(crate) fn show_store_info(conn: &Connection, store: &Path) -> anyhow::Result<()> {
+    let version = get_schema_version(conn)?;
+    let generations: i64 = conn.query_row("SELECT COUNT(*) FROM compdbs", [], |row| row.get(0))?;
+    let current = get_current_generation(conn)?;
+    let size = fs::metadata(store)
+        .context(format!("Failed to stat {}", store.display()))?
+        .len();
+    println!("{0}Store:{0:#}          {1}", STYLE_BOLD, store.display());
+    println!("{0}Schema version:{0:#} {1}", STYLE_BOLD, version);
+    println!("{0}Generations:{0:#}    {1}", STYLE_BOLD, generations);
+    println!(
+        "{0}Current:{0:#}        {1}",
+        STYLE_BOLD,
+        current.map_or("none".to_string(), |x| x.to_string())
+    );
+    let (logical, stored) = get_store_sizes(conn)?;
+    println!(
+        "{0}Size:{0:#}           {1} on disk, {2} of compilation databases stored as {3}",
+        STYLE_BOLD,
+        utils::human_bytes(size),
+        utils::human_bytes(logical),
+        utils::human_bytes(stored)
+    );
+    Ok(())
 }
 
-#[allow(dead_code)]
-impl Table {
-    pub(crate) fn new() -> Self {
-        Table {
-            col_generation: TableColumn {
-                header: "Generation".to_string(),
-                series: Vec::new(),
-            },
-            col_branch: TableColumn {
-                header: "Branch".to_string(),
-                series: Vec::new(),
-            },
-            col_commit: TableColumn {
-                header: "Commit".to_string(),
-                series: Vec::new(),
-            },
-            col_target: TableColumn {
-                header: "Target".to_string(),
-                series: Vec::new(),
-            },
-            col_date: TableColumn {
-                header: "Date".to_string(),
-                series: Vec::new(),
-            },
-            col_remark: TableColumn {
-                header: "Remark".to_string(),
-                series: Vec::new(),
-            },
-            indicator: "*".to_string(),
-            num_rows: 0,
+/// Serialize a JSON value with the keys of objects sorted, so that equal values always produce
+/// the same text whatever the key order they came with
+fn canonical_json(value: &serde_json::Value) -> String {
+    fn sorted(value: &serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(map) => {
+                let mut keys: Vec<&String> = map.keys().collect();
+                keys.sort();
+                serde_json::Value::Object(
+                    keys.into_iter()
+                        .map(|k| (k.clone(), sorted(&map[k])))
+                        .collect(),
+                )
+            }
+            serde_json::Value::Array(list) => {
+                serde_json::Value::Array(list.iter().map(sorted).collect())
+            }
+            v => v.clone(),
         }
     }
+    sorted(value).to_string()
+}
 
-    pub(crate) fn num_rows(&self) -> usize {
-        self.num_rows
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+/// How large a compilation database is when compressed standalone. Sized from its entries
+/// rather than the bytes given, so that the sizes of generations archived, rewritten and
+/// migrated from older stores compare with each other.
+fn compressed_size(entries: &[serde_json::Value]) -> anyhow::Result<i64> {
+    let compressed = encode_all(&serde_json::to_vec_pretty(entries)?[..], 0)?;
+    Ok(compressed.len() as i64)
+}
+
+/// Put a compilation database into the content-addressed tables and return its digest.
+///
+/// The compilation database is identified by the digest of its canonical JSON, identical ones
+/// share one manifest. Entries are identified by the hash of their canonical JSON and stored
+/// once across all generations: the entries not seen before are compressed together into a new
+/// pack, and the manifest lists the entries of the compilation database in order.
+fn store_compdb(conn: &Connection, content: &[u8]) -> anyhow::Result<String> {
+    let compdb: serde_json::Value =
+        serde_json::from_slice(content).context("Failed to deserialize compilation database")?;
+    let entries = compdb
+        .as_array()
+        .context("Compilation database is not a JSON array")?;
+    let canonical: Vec<String> = entries.iter().map(canonical_json).collect();
+    let digest = sha256_hex(format!("[{}]", canonical.join(",")).as_bytes());
+    let exists: Option<String> = conn
+        .query_row(
+            "SELECT digest FROM manifests WHERE digest=?1",
+            [&digest],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if exists.is_some() {
+        return Ok(digest);
     }
 
-    pub(crate) fn is_empty(&self) -> bool {
-        self.num_rows() == 0
+    // Resolve the known entries, and collect the new ones into a pack
+    let mut lookup = conn.prepare("SELECT id FROM records WHERE hash=?1")?;
+    let mut ids: Vec<Option<i64>> = Vec::with_capacity(canonical.len());
+    let mut pending: IndexMap<String, usize> = IndexMap::new();
+    let mut pack: Vec<&serde_json::Value> = Vec::new();
+    let mut hashes = Vec::with_capacity(canonical.len());
+    for (entry, text) in entries.iter().zip(canonical.iter()) {
+        let hash = sha256_hex(text.as_bytes());
+        let id: Option<i64> = lookup.query_row([&hash], |row| row.get(0)).optional()?;
+        if id.is_none() && !pending.contains_key(&hash) {
+            pending.insert(hash.clone(), pack.len());
+            pack.push(entry);
+        }
+        ids.push(id);
+        hashes.push(hash);
+    }
+    if !pack.is_empty() {
+        let data = encode_all(serde_json::to_string(&pack)?.as_bytes(), 0)?;
+        conn.execute("INSERT INTO packs (data) VALUES (?1)", [data])?;
+        let pack_id = conn.last_insert_rowid();
+        let mut insert =
+            conn.prepare("INSERT INTO records (hash, pack, idx) VALUES (?1, ?2, ?3)")?;
+        for (hash, idx) in pending.iter() {
+            insert.execute(params![hash, pack_id, *idx as i64])?;
+        }
+    }
+    let mut record_ids = Vec::with_capacity(ids.len());
+    for (id, hash) in ids.into_iter().zip(hashes.iter()) {
+        record_ids.push(match id {
+            Some(v) => v,
+            None => lookup.query_row([hash], |row| row.get(0))?,
+        });
     }
 
-    /// Insert a row at the tail of the table
+    let records = encode_all(serde_json::to_string(&record_ids)?.as_bytes(), 0)?;
+    conn.execute(
+        "INSERT INTO manifests (digest, records, entries, size, compressed) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            digest,
+            records,
+            record_ids.len() as i64,
+            content.len() as i64,
+            compressed_size(entries)?
+        ],
+    )?;
+    Ok(digest)
+}
+
+/// Reassemble the entries of a compilation database from the content-addressed tables
+fn load_compdb(conn: &Connection, digest: &str) -> anyhow::Result<Vec<serde_json::Value>> {
+    let records: Vec<u8> = conn
+        .query_row(
+            "SELECT records FROM manifests WHERE digest=?1",
+            [digest],
+            |row| row.get(0),
+        )
+        .optional()?
+        .context(format!("Manifest {} missing from store", digest))?;
+    let record_ids: Vec<i64> = serde_json::from_slice(&decode_all(&records[..])?)?;
+
+    let mut lookup = conn.prepare("SELECT pack, idx FROM records WHERE id=?1")?;
+    let mut load_pack = conn.prepare("SELECT data FROM packs WHERE id=?1")?;
+    let mut packs: HashMap<i64, Vec<serde_json::Value>> = HashMap::new();
+    let mut entries = Vec::with_capacity(record_ids.len());
+    for id in record_ids.iter() {
+        let (pack_id, idx): (i64, i64) = lookup
+            .query_row([id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?
+            .context(format!("Entry {} missing from store", id))?;
+        let pack = match packs.entry(pack_id) {
+            Entry::Occupied(v) => v.into_mut(),
+            Entry::Vacant(v) => {
+                let data: Vec<u8> = load_pack.query_row([pack_id], |row| row.get(0))?;
+                v.insert(serde_json::from_slice(&decode_all(&data[..])?)?)
+            }
+        };
+        let entry = pack
+            .get(idx as usize)
+            .context(format!("Entry {} missing from pack {}", id, pack_id))?;
+        entries.push(entry.clone());
+    }
+    Ok(entries)
+}
+
+/// Drop manifests, entries and packs no generation refers to any more. Packs left with some of
+/// their entries dropped are rewritten with the live entries only.
+fn prune_store(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "DELETE FROM manifests WHERE digest NOT IN (SELECT digest FROM compdbs)",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TEMP TABLE IF NOT EXISTS live_records (id INTEGER PRIMARY KEY)",
+        (),
+    )?;
+    conn.execute("DELETE FROM live_records", ())?;
+    {
+        let mut stmt = conn.prepare("SELECT records FROM manifests")?;
+        let mut insert = conn.prepare("INSERT OR IGNORE INTO live_records (id) VALUES (?1)")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let records: Vec<u8> = row.get(0)?;
+            let record_ids: Vec<i64> = serde_json::from_slice(&decode_all(&records[..])?)?;
+            for id in record_ids.iter() {
+                insert.execute([id])?;
+            }
+        }
+    }
+    let touched = conn
+        .prepare("SELECT DISTINCT pack FROM records WHERE id NOT IN (SELECT id FROM live_records)")?
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<Result<Vec<i64>, _>>()?;
+    conn.execute(
+        "DELETE FROM records WHERE id NOT IN (SELECT id FROM live_records)",
+        (),
+    )?;
+    conn.execute(
+        "DELETE FROM packs WHERE id NOT IN (SELECT DISTINCT pack FROM records)",
+        (),
+    )?;
+    conn.execute("DROP TABLE live_records", ())?;
+
+    for pack_id in touched.into_iter() {
+        let data: Option<Vec<u8>> = conn
+            .query_row("SELECT data FROM packs WHERE id=?1", [pack_id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let Some(data) = data else {
+            continue;
+        };
+        repack(conn, pack_id, &data)?;
+    }
+    Ok(())
+}
+
+/// Rewrite the pack with the entries still referred to, in their original order
+fn repack(conn: &Connection, pack_id: i64, data: &[u8]) -> anyhow::Result<()> {
+    let entries: Vec<serde_json::Value> = serde_json::from_slice(&decode_all(data)?)?;
+    let live = conn
+        .prepare("SELECT id, idx FROM records WHERE pack=?1 ORDER BY idx")?
+        .query_map([pack_id], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+        })?
+        .collect::<Result<Vec<(i64, i64)>, _>>()?;
+    let mut pack = Vec::with_capacity(live.len());
+    for (id, idx) in live.iter() {
+        pack.push(
+            entries
+                .get(*idx as usize)
+                .context(format!("Entry {} missing from pack {}", id, pack_id))?,
+        );
+    }
+    let data = encode_all(serde_json::to_string(&pack)?.as_bytes(), 0)?;
+    conn.execute(
+        "UPDATE packs SET data=?1 WHERE id=?2",
+        params![data, pack_id],
+    )?;
+    let mut update = conn.prepare("UPDATE records SET idx=?1 WHERE id=?2")?;
+    for (idx, (id, _)) in live.iter().enumerate() {
+        update.execute(params![idx as i64, id])?;
+    }
+    Ok(())
+}
+
+/// Sizes of the store in bytes, returns the total size of the compilation databases of all
+/// generations and the size actually stored after deduplication
+pub(crate) fn get_store_sizes(conn: &Connection) -> anyhow::Result<(u64, u64)> {
+    let logical: i64 = conn.query_row(
+        "SELECT COALESCE(SUM(m.size), 0) FROM compdbs c JOIN manifests m ON c.digest=m.digest",
+        [],
+        |row| row.get(0),
+    )?;
+    let stored: i64 = conn.query_row(
+        "SELECT (SELECT COALESCE(SUM(LENGTH(data)), 0) FROM packs) + (SELECT COALESCE(SUM(LENGTH(records)), 0) FROM manifests)",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok((logical as u64, stored as u64))
+}
+
+/// Insert a generation, returns the generation id
+fn insert_generation(
+    conn: &Connection,
+    branch: &str,
+    commit_id: &str,
+    target: &str,
+    timestamp: i64,
+    compdb: &[u8],
+    remark: Option<&str>,
+) -> anyhow::Result<i64> {
+    let digest = store_compdb(conn, compdb)?;
+    conn.execute("INSERT INTO compdbs (branch, revision, target, timestamp, digest, remark) VALUES (?1, ?2, ?3, ?4, ?5, ?6)", params![
+        branch, commit_id, target, timestamp, digest, remark
+    ])?;
+    Ok(conn.last_insert_rowid())
+}
+
+const STYLE_BOLD: Style = Style::new().bold();
+const STYLE_YELLOW: Style = Style::new().fg_color(Some(Color::Ansi256(Ansi256Color(3))));
+const STYLE_GREEN: Style = Style::new().fg_color(Some(Color::Ansi256(Ansi256Color(2))));
+const STYLE_RED: Style = Style::new().fg_color(Some(Color::Ansi256(Ansi256Color(1))));
+
+pub(crate) struct TableColumn<T>
+where
+    T: ToString,
+{
+    header: String,
+    series: Vec<T>,
+}
+
+impl<T: ToString> TableColumn<T> {
+    pub(crate) fn display_width_header(&self) -> usize {
+        self.header.chars().count()
+    }
+
+    pub(crate) fn display_width_series(&self) -> usize {
+        self.series
+            .iter()
+            .fold(0, |r, x| cmp::max(r, x.to_string().chars().count()))
+    }
+
+    pub(crate) fn display_width(&self) -> usize {
+        cmp::max(self.display_width_header(), self.display_width_series())
+    }
+}
+
+pub(crate) struct Table {
+    col_generation: TableColumn<i64>,
+    col_branch: TableColumn<String>,
+    col_commit: TableColumn<String>,
+    col_target: TableColumn<String>,
+    col_date: TableColumn<String>,
+    col_entries: TableColumn<i64>,
+    col_size: TableColumn<String>,
+    col_remark: TableColumn<String>,
+    indicator: String,
+    num_rows: usize,
+}
+
+#[allow(dead_code)]
+impl Table {
+    pub(crate) fn new() -> Self {
+        Table {
+            col_generation: TableColumn {
+                header: "Generation".to_string(),
+                series: Vec::new(),
+            },
+            col_branch: TableColumn {
+                header: "Branch".to_string(),
+                series: Vec::new(),
+            },
+            col_commit: TableColumn {
+                header: "Commit".to_string(),
+                series: Vec::new(),
+            },
+            col_target: TableColumn {
+                header: "Target".to_string(),
+                series: Vec::new(),
+            },
+            col_date: TableColumn {
+                header: "Date".to_string(),
+                series: Vec::new(),
+            },
+            col_entries: TableColumn {
+                header: "Entries".to_string(),
+                series: Vec::new(),
+            },
+            col_size: TableColumn {
+                header: "Size".to_string(),
+                series: Vec::new(),
+            },
+            col_remark: TableColumn {
+                header: "Remark".to_string(),
+                series: Vec::new(),
+            },
+            indicator: "*".to_string(),
+            num_rows: 0,
+        }
+    }
+
+    pub(crate) fn num_rows(&self) -> usize {
+        self.num_rows
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.num_rows() == 0
+    }
+
+    /// Insert a row at the tail of the table
     pub(crate) fn push_row(&mut self, item: CompdbStoreItem) {
         let date = chrono::Local
             .timestamp_opt(item.timestamp, 0)
             .unwrap()
             .format("%Y-%m-%dT%H:%M:%S")
             .to_string();
+        let size = utils::human_bytes(item.compressed as u64);
         let remark = item.remark.unwrap_or_default();
         self.col_generation.series.push(item.generation);
         self.col_branch.series.push(item.branch);
         self.col_commit.series.push(item.commit);
         self.col_target.series.push(item.target);
         self.col_date.series.push(date);
+        self.col_entries.series.push(item.entries);
+        self.col_size.series.push(size);
         self.col_remark.series.push(remark);
         self.num_rows += 1;
     }
@@ -671,25 +2193,30 @@ impl Table {
             .unwrap()
             .format("%Y-%m-%dT%H:%M:%S")
             .to_string();
+        let size = utils::human_bytes(item.compressed as u64);
         let remark = item.remark.unwrap_or_default();
         self.col_generation.series.insert(i, item.generation);
         self.col_branch.series.insert(i, item.branch);
         self.col_commit.series.insert(i, item.commit);
         self.col_target.series.insert(i, item.target);
         self.col_date.series.insert(i, date);
+        self.col_entries.series.insert(i, item.entries);
+        self.col_size.series.insert(i, size);
         self.col_remark.series.insert(i, remark);
         self.num_rows += 1;
     }
 
     /// Get a row from the table, with the following fields:
-    /// (generation, branch, revision, target, date and remark)
-    pub(crate) fn get_row(&self, i: usize) -> (i64, &str, &str, &str, &str, &str) {
+    /// (generation, branch, revision, target, date, entries, size and remark)
+    pub(crate) fn get_row(&self, i: usize) -> (i64, &str, &str, &str, &str, i64, &str, &str) {
         (
             self.col_generation.series[i],
             &self.col_branch.series[i],
             &self.col_commit.series[i],
             &self.col_target.series[i],
             &self.col_date.series[i],
+            self.col_entries.series[i],
+            &self.col_size.series[i],
             &self.col_remark.series[i],
         )
     }
@@ -701,31 +2228,178 @@ impl Table {
             self.col_generation.series.remove(i);
             self.col_branch.series.remove(i);
             self.col_commit.series.remove(i);
+            self.col_target.series.remove(i);
             self.col_date.series.remove(i);
+            self.col_entries.series.remove(i);
+            self.col_size.series.remove(i);
             self.col_remark.series.remove(i);
+            self.num_rows -= 1;
         }
     }
 }
 
-pub(crate) fn list_generations(conn: &Connection) -> anyhow::Result<()> {
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum)]
+pub(crate) enum ListFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum)]
+pub(crate) enum SortKey {
+    Generation,
+    Date,
+    Branch,
+    Target,
+    Entries,
+    Size,
+}
+
+/// Options to filter, sort and format the listed generations
+#[derive(Clone, Debug)]
+pub(crate) struct ListOptions {
+    pub(crate) branch: Option<String>,
+    pub(crate) target: Option<String>,
+    /// Unix timestamp before which generations are left out
+    pub(crate) since: Option<i64>,
+    pub(crate) remark: Option<Regex>,
+    pub(crate) sort: SortKey,
+    pub(crate) reverse: bool,
+    pub(crate) format: ListFormat,
+}
+
+/// Generation as listed in JSON or CSV
+#[derive(Clone, Debug, Serialize)]
+struct GenerationRow {
+    generation: i64,
+    current: bool,
+    branch: String,
+    revision: String,
+    target: String,
+    timestamp: i64,
+    date: String,
+    entries: i64,
+    size: i64,
+    compressed: i64,
+    remark: String,
+}
+
+/// Parse the time of `--since`, either a date like 2025-03-01, a local time like
+/// 2025-03-01T08:00:00, or a period back from now like 12h, 7d or 2w
+pub(crate) fn parse_since(text: &str) -> anyhow::Result<i64> {
+    let now = chrono::Local::now().timestamp();
+    if let Some(n) = text.strip_suffix(['h', 'd', 'w'])
+        && !n.is_empty()
+        && n.chars().all(|c| c.is_ascii_digit())
+    {
+        let secs = match text.chars().last() {
+            Some('h') => 3600,
+            Some('d') => 86400,
+            _ => 7 * 86400,
+        };
+        return Ok(now - n.parse::<i64>()? * secs);
+    }
+    let time = if let Ok(v) = chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S") {
+        v
+    } else if let Ok(v) = chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        v.and_hms_opt(0, 0, 0).unwrap()
+    } else {
+        bail!(
+            "Invalid time: {}, expecting 2025-03-01, 2025-03-01T08:00:00 or 7d",
+            text
+        );
+    };
+    Ok(chrono::Local
+        .from_local_datetime(&time)
+        .earliest()
+        .context(format!("Invalid local time: {}", text))?
+        .timestamp())
+}
+
+pub(crate) fn list_generations(conn: &Connection, options: &ListOptions) -> anyhow::Result<()> {
     // Database querying
-    let mut stmt = conn.prepare("SELECT generation, branch, revision, target, timestamp, remark FROM compdbs ORDER BY generation DESC")?;
-    let data_iter = stmt.query_map([], |row| {
-        Ok(CompdbStoreItem {
-            generation: row.get(0)?,
-            branch: row.get(1)?,
-            commit: row.get(2)?,
-            target: row.get(3)?,
-            timestamp: row.get(4)?,
-            compdb: Vec::new(), // Fake content as the content in this field is huge
-            remark: row.get(5)?,
-        })
-    })?;
+    let mut stmt = conn.prepare(
+        "SELECT c.generation, c.branch, c.revision, c.target, c.timestamp, c.remark, m.entries, m.size, m.compressed FROM compdbs c JOIN manifests m ON c.digest=m.digest WHERE (?1 IS NULL OR c.branch=?1) AND (?2 IS NULL OR c.target=?2) AND (?3 IS NULL OR c.timestamp>=?3) ORDER BY c.generation DESC",
+    )?;
+    let data_iter = stmt.query_map(
+        params![options.branch, options.target, options.since],
+        |row| {
+            Ok(CompdbStoreItem {
+                generation: row.get(0)?,
+                branch: row.get(1)?,
+                commit: row.get(2)?,
+                target: row.get(3)?,
+                timestamp: row.get(4)?,
+                compdb: Vec::new(), // Fake content as the content in this field is huge
+                remark: row.get(5)?,
+                entries: row.get(6)?,
+                size: row.get(7)?,
+                compressed: row.get(8)?,
+            })
+        },
+    )?;
+    let mut items = Vec::new();
+    for item in data_iter {
+        let item = item?;
+        if let Some(pattern) = options.remark.as_ref()
+            && !pattern.is_match(item.remark.as_deref().unwrap_or_default())
+        {
+            continue;
+        }
+        items.push(item);
+    }
+
+    // Newest, biggest or alphabetically first, ties kept in generation order
+    match options.sort {
+        SortKey::Generation => {}
+        SortKey::Date => items.sort_by_key(|x| cmp::Reverse(x.timestamp)),
+        SortKey::Branch => items.sort_by(|a, b| a.branch.cmp(&b.branch)),
+        SortKey::Target => items.sort_by(|a, b| a.target.cmp(&b.target)),
+        SortKey::Entries => items.sort_by_key(|x| cmp::Reverse(x.entries)),
+        SortKey::Size => items.sort_by_key(|x| cmp::Reverse(x.compressed)),
+    }
+    if options.reverse {
+        items.reverse();
+    }
+    let current = get_current_generation(conn)?;
+
+    if let ListFormat::Json | ListFormat::Csv = options.format {
+        let rows: Vec<GenerationRow> = items
+            .into_iter()
+            .map(|item| GenerationRow {
+                generation: item.generation,
+                current: current == Some(item.generation),
+                branch: item.branch,
+                revision: item.commit,
+                target: item.target,
+                timestamp: item.timestamp,
+                date: chrono::Local
+                    .timestamp_opt(item.timestamp, 0)
+                    .unwrap()
+                    .format("%Y-%m-%dT%H:%M:%S")
+                    .to_string(),
+                entries: item.entries,
+                size: item.size,
+                compressed: item.compressed,
+                remark: item.remark.unwrap_or_default(),
+            })
+            .collect();
+        if let ListFormat::Json = options.format {
+            println!("{}", serde_json::to_string_pretty(&rows)?);
+        } else {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            for row in rows.iter() {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        }
+        return Ok(());
+    }
 
     // Formatting
     let mut table = Table::new();
-    for item in data_iter {
-        table.push_row(item?);
+    for item in items {
+        table.push_row(item);
     }
 
     if table.is_empty() {
@@ -743,24 +2417,27 @@ pub(crate) fn list_generations(conn: &Connection) -> anyhow::Result<()> {
     let commit_cols = table.col_commit.display_width();
     let target_cols = table.col_target.display_width();
     let date_cols = table.col_date.display_width();
+    let entries_cols = table.col_entries.display_width();
+    let size_cols = table.col_size.display_width();
     let remark_cols = table.col_remark.display_width();
     println!(
-        "{0}{1:<generation_cols$}   {2:<branch_cols$}   {3:<commit_cols$}   {4:<target_cols$}   {5:<date_cols$}   {6:<remark_cols$}{0:#}",
+        "{0}{1:<generation_cols$}   {2:<branch_cols$}   {3:<commit_cols$}   {4:<target_cols$}   {5:<date_cols$}   {6:>entries_cols$}   {7:>size_cols$}   {8:<remark_cols$}{0:#}",
         STYLE_BOLD,
         table.col_generation.header,
         table.col_branch.header,
         table.col_commit.header,
         table.col_target.header,
         table.col_date.header,
+        table.col_entries.header,
+        table.col_size.header,
         table.col_remark.header,
     );
     let generation_pad_cols =
         generation_cols - generation_id_cols - table.indicator.chars().count() - 1;
-    let current = get_current_generation(conn)?;
     for i in 0..table.num_rows {
-        let (g, b, r, t, d, m) = table.get_row(i);
+        let (g, b, r, t, d, e, z, m) = table.get_row(i);
         println!(
-            "{1:<generation_id_cols$}{2:generation_pad_cols$} {0}{8:indicator_cols$}{0:#}   {3:branch_cols$}   {4:<commit_cols$}   {5:target_cols$}   {6:<date_cols$}   {7:<remark_cols$}",
+            "{1:<generation_id_cols$}{2:generation_pad_cols$} {0}{10:indicator_cols$}{0:#}   {3:branch_cols$}   {4:<commit_cols$}   {5:target_cols$}   {6:<date_cols$}   {7:>entries_cols$}   {8:>size_cols$}   {9:<remark_cols$}",
             STYLE_YELLOW,
             g,
             "",
@@ -768,6 +2445,8 @@ pub(crate) fn list_generations(conn: &Connection) -> anyhow::Result<()> {
             r,
             t,
             d,
+            e,
+            z,
             m,
             if let Some(current) = current {
                 if current == g {
@@ -780,117 +2459,2007 @@ pub(crate) fn list_generations(conn: &Connection) -> anyhow::Result<()> {
             },
         );
     }
+    let (logical, stored) = get_store_sizes(conn)?;
+    println!(
+        "\n{} generations, {} in total, {} stored after deduplication",
+        table.num_rows,
+        utils::human_bytes(logical),
+        utils::human_bytes(stored)
+    );
 
     Ok(())
 }
 
-#[derive(Clone, Debug)]
-pub(crate) enum DelOpt {
-    Generations(Vec<i64>),
-    Oldest(i64),
-    Newest(i64),
-    All,
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum)]
+pub(crate) enum DiffFormat {
+    Text,
+    Json,
 }
 
-/// Delete a compilation database generation from the store
-pub(crate) fn remove_generation(conn: &Connection, opt: DelOpt) -> anyhow::Result<usize> {
-    let rows = match opt {
-        DelOpt::Generations(v) => conn.execute(
-            format!(
-                "DELETE FROM compdbs WHERE generation IN ({})",
-                v.iter().map(|_| "?").collect::<Vec<&str>>().join(", ")
-            )
-            .as_str(),
-            params_from_iter(v.iter()),
-        )?,
-        DelOpt::All => conn.execute("DELETE FROM compdbs", ())?,
-        DelOpt::Newest(n) => conn.execute(
-            "DELETE FROM compdbs WHERE generation IN (SELECT generation FROM compdbs ORDER BY generation DESC LIMIT ?1)",
-            [n]
-        )?,
-        DelOpt::Oldest(n) => conn.execute("DELETE FROM compdbs WHERE generation in (SELECT generation FROM compdbs ORDER BY generation ASC LIMIT ?1)", [n])?
-    };
-    conn.execute("VACUUM", ())?;
-    Ok(rows)
+/// Flag changes of a file compiled in both generations
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct FileDiff {
+    file: String,
+    flags_added: Vec<String>,
+    flags_removed: Vec<String>,
 }
 
-pub(crate) fn use_generation(conn: &Connection, generation: i64) -> anyhow::Result<()> {
-    let pb = ProgressBar::no_length().with_style(ProgressStyle::with_template(
-        format!("Switching to generation {}...{{msg}}", generation).as_str(),
-    )?);
-    pb.tick();
-    let item: Option<Vec<u8>> = conn
-        .query_row(
-            "SELECT compdb FROM compdbs WHERE generation=?1",
-            [generation],
-            |row| row.get(0),
-        )
-        .optional()?;
-    let item = item.context("Generation not available")?;
-    let compile_commands = decode_all(&item[..])?;
-    fs::write(COMPDB_FILE, compile_commands)?;
-    set_current_generation(conn, generation)?;
-    pb.finish_with_message("ok");
-    Ok(())
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct CompdbDiff {
+    from: i64,
+    to: i64,
+    files_added: Vec<String>,
+    files_removed: Vec<String>,
+    files_changed: Vec<FileDiff>,
+    files_unchanged: usize,
 }
 
-/// Archive the compilation database into store as a new generation and
-/// optionally update history table
-pub(crate) fn archive_compdb<P>(
-    conn: &Connection,
-    branch: &str,
-    commit_id: &str,
-    target: &str,
-    compdb: P,
-) -> anyhow::Result<usize>
-where
-    P: AsRef<Path>,
-{
-    let compdb = compdb.as_ref();
-    let content = fs::read_to_string(compdb)?;
-    let compressed = encode_all(content.as_bytes(), 0)?;
-    let rows = add_compdb(conn, branch, commit_id, target, &compressed)?;
-    Ok(rows)
-}
+/// Options whose value comes in the next argument, kept together with it when diffing
+const SEPARATE_VALUE_OPTIONS: [&str; 14] = [
+    "-D",
+    "-U",
+    "-I",
+    "-include",
+    "-imacros",
+    "-isystem",
+    "-iquote",
+    "-idirafter",
+    "-isysroot",
+    "-x",
+    "-target",
+    "-MF",
+    "-MT",
+    "-MQ",
+];
 
-pub(crate) fn merge_compdb<T: AsRef<Path>>(files: Vec<T>) -> anyhow::Result<()> {
-    let mut merged: serde_json::Value = json!([]);
-    let compdb = Path::new(COMPDB_FILE);
-    if compdb.is_file() {
-        let file = fs::File::open(compdb)?;
-        let reader = io::BufReader::new(file);
-        let content: serde_json::Value = serde_json::from_reader(reader)
-            .context(format!("Failed to deserialize {}", compdb.display()))?;
-        for elem in content.as_array().unwrap() {
-            merged.as_array_mut().unwrap().push(elem.clone());
-        }
-    }
-    for item in files.iter().map(|x| x.as_ref()) {
-        if !item.is_file() {
-            eprintln!("File {} not found", item.display());
+/// Flags of a compile command for comparison, without the compiler, the output and the file
+fn compile_flags(record: &CompdbRecord) -> anyhow::Result<Vec<String>> {
+    let args = record.arguments()?;
+    let directory = Path::new(&record.directory);
+    let file = normalize_path(directory.join(&record.file));
+    let mut flags = Vec::new();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "-o" {
+            iter.next();
+        } else if SEPARATE_VALUE_OPTIONS.contains(&arg.as_str()) {
+            match iter.next() {
+                Some(v) => flags.push(format!("{} {}", arg, v)),
+                None => flags.push(arg.clone()),
+            }
+        } else if joined_output(arg).is_some() || normalize_path(directory.join(arg)) == file {
             continue;
-        }
-        let file = fs::File::open(item)?;
-        let reader = io::BufReader::new(file);
-        let content: serde_json::Value = serde_json::from_reader(reader)
-            .context(format!("Failed to deserialize {}", item.display()))?;
-        for elem in content.as_array().unwrap() {
-            merged.as_array_mut().unwrap().push(elem.clone());
+        } else {
+            flags.push(arg.clone());
         }
     }
-
-    fs::write(compdb, serde_json::to_string_pretty(&merged)?)?;
-    Ok(())
+    Ok(flags)
 }
 
-/// Remark a compilation database generation
-///
-/// Returns the number of affected rows, non-zero on success, zero on failure
-pub(crate) fn remark_generation(
+/// Index the entries of a generation by the normalized file path, the first entry wins
+fn index_generation(
     conn: &Connection,
-    generation_id: i64,
-    remark: &str,
-) -> anyhow::Result<usize> {
+    generation: i64,
+) -> anyhow::Result<IndexMap<String, CompdbRecord>> {
+    let records = read_generation(conn, generation)?;
+    let mut indexed = IndexMap::new();
+    for record in records.into_iter() {
+        let file = normalize_path(Path::new(&record.directory).join(&record.file))
+            .to_string_lossy()
+            .to_string();
+        indexed.entry(file).or_insert(record);
+    }
+    Ok(indexed)
+}
+
+/// Compare two generations, reporting files added or removed and flags changed per file
+pub(crate) fn diff_generations(
+    conn: &Connection,
+    from: i64,
+    to: i64,
+) -> anyhow::Result<CompdbDiff> {
+    let old = index_generation(conn, from)?;
+    let new = index_generation(conn, to)?;
+    let mut diff = CompdbDiff {
+        from,
+        to,
+        files_added: new
+            .keys()
+            .filter(|x| !old.contains_key(*x))
+            .cloned()
+            .collect(),
+        files_removed: old
+            .keys()
+            .filter(|x| !new.contains_key(*x))
+            .cloned()
+            .collect(),
+        files_changed: Vec::new(),
+        files_unchanged: 0,
+    };
+    for (file, old_record) in old.iter() {
+        let Some(new_record) = new.get(file) else {
+            continue;
+        };
+        let old_flags = compile_flags(old_record)?;
+        let new_flags = compile_flags(new_record)?;
+        let flags_added: Vec<String> = new_flags
+            .iter()
+            .filter(|x| !old_flags.contains(x))
+            .cloned()
+            .collect();
+        let flags_removed: Vec<String> = old_flags
+            .iter()
+            .filter(|x| !new_flags.contains(x))
+            .cloned()
+            .collect();
+        if flags_added.is_empty() && flags_removed.is_empty() {
+            diff.files_unchanged += 1;
+            continue;
+        }
+        diff.files_changed.push(FileDiff {
+            file: file.clone(),
+            flags_added,
+            flags_removed,
+        });
+    }
+    Ok(diff)
+}
+
+pub(crate) fn print_diff(diff: &CompdbDiff, format: DiffFormat) -> anyhow::Result<()> {
+    if let DiffFormat::Json = format {
+        println!("{}", serde_json::to_string_pretty(diff)?);
+        return Ok(());
+    }
+
+    println!("{0}--- generation {1}{0:#}", STYLE_BOLD, diff.from);
+    println!("{0}+++ generation {1}{0:#}", STYLE_BOLD, diff.to);
+    for file in diff.files_added.iter() {
+        println!("{0}A {1}{0:#}", STYLE_GREEN, file);
+    }
+    for file in diff.files_removed.iter() {
+        println!("{0}D {1}{0:#}", STYLE_RED, file);
+    }
+    for item in diff.files_changed.iter() {
+        println!("{0}M {1}{0:#}", STYLE_YELLOW, item.file);
+        for flag in item.flags_added.iter() {
+            println!("    {0}+ {1}{0:#}", STYLE_GREEN, flag);
+        }
+        for flag in item.flags_removed.iter() {
+            println!("    {0}- {1}{0:#}", STYLE_RED, flag);
+        }
+    }
+    println!(
+        "{} added, {} removed, {} changed, {} unchanged",
+        diff.files_added.len(),
+        diff.files_removed.len(),
+        diff.files_changed.len(),
+        diff.files_unchanged
+    );
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Severity {
+    Warning,
+    Error,
+}
+
+/// Problem found in an entry of a compilation database
+#[derive(Clone, Debug)]
+pub(crate) struct Finding {
+    severity: Severity,
+    file: String,
+    message: String,
+}
+
+/// Options whose value differs from file to file by nature, left out when comparing siblings
+const PER_FILE_OPTIONS: [&str; 5] = ["-MF ", "-MT ", "-MQ ", "-Wp,-MD,", "-Wp,-MMD,"];
+
+/// Find the compiler by the runner, which is where the build runs and the compiler lives. A
+/// compiler with a path is resolved against the directory of the entry, others by PATH.
+fn resolve_compiler(runner: &Runner, repo_root: &Path, compiler: &str) -> bool {
+    let command = if compiler.contains('/') {
+        format!("test -x {}", utils::shell_quote(compiler))
+    } else {
+        format!("command -v {}", utils::shell_quote(compiler))
+    };
+    run_probe(runner, repo_root, repo_root, &command).is_ok()
+}
+
+/// Lint a compilation database, reporting entries which would mislead language servers. The
+/// compilers are looked up by the runner.
+pub(crate) fn check_compdb(
+    records: &[CompdbRecord],
+    runner: &Runner,
+    repo_root: &Path,
+) -> Vec<Finding> {
+    let mangled = Regex::new(r"\$[({]|>>:").unwrap();
+    let mut findings = Vec::new();
+    let mut compilers: HashMap<String, bool> = HashMap::new();
+    let mut by_file: IndexMap<PathBuf, Vec<usize>> = IndexMap::new();
+    let mut by_dir: IndexMap<PathBuf, Vec<(usize, Vec<String>)>> = IndexMap::new();
+
+    for (i, record) in records.iter().enumerate() {
+        let directory = normalize_path(&record.directory);
+        let file = normalize_path(directory.join(&record.file));
+        let mut report = |severity, message: String| {
+            findings.push(Finding {
+                severity,
+                file: file.to_string_lossy().to_string(),
+                message,
+            })
+        };
+        if !directory.is_dir() {
+            report(
+                Severity::Error,
+                format!("directory {} does not exist", directory.display()),
+            );
+        } else if !file.is_file() {
+            report(Severity::Error, "file does not exist".to_string());
+        }
+
+        // Leftovers of make which the built-in engine failed to expand or strip
+        let command = record.command();
+        let is_mangled = mangled.is_match(&command);
+        if let Some(m) = mangled.find(&command) {
+            report(
+                Severity::Error,
+                format!(
+                    "command contains `{}` which looks mangled: {}",
+                    m.as_str(),
+                    command
+                ),
+            );
+        }
+        let args = match record.arguments() {
+            Ok(v) => v,
+            Err(e) => {
+                report(Severity::Error, format!("command can't be parsed: {}", e));
+                continue;
+            }
+        };
+        match args.first() {
+            Some(compiler) => {
+                // Each compiler is probed once, by its absolute path when it has a path
+                let key = if compiler.contains('/') {
+                    normalize_path(directory.join(compiler))
+                        .to_string_lossy()
+                        .to_string()
+                } else {
+                    compiler.clone()
+                };
+                let resolved = *compilers
+                    .entry(key)
+                    .or_insert_with_key(|x| resolve_compiler(runner, repo_root, x));
+                if !resolved {
+                    report(
+                        Severity::Error,
+                        format!("compiler {} can't be resolved", compiler),
+                    );
+                }
+            }
+            None => report(Severity::Error, "command is empty".to_string()),
+        }
+
+        by_file.entry(file).or_default().push(i);
+        if !is_mangled && let Ok(flags) = compile_flags(record) {
+            let flags = flags
+                .into_iter()
+                .filter(|x| !PER_FILE_OPTIONS.iter().any(|y| x.starts_with(y)))
+                .collect();
+            by_dir.entry(directory).or_default().push((i, flags));
+        }
+    }
+
+    // Language servers pick one of the entries for a file, the others are ignored silently
+    for (file, indices) in by_file.iter() {
+        if indices.len() < 2 {
+            continue;
+        }
+        let flags: Vec<Vec<String>> = indices
+            .iter()
+            .map(|i| compile_flags(&records[*i]).unwrap_or_default())
+            .collect();
+        findings.push(Finding {
+            severity: Severity::Warning,
+            file: file.to_string_lossy().to_string(),
+            message: format!(
+                "listed {} times{}",
+                indices.len(),
+                if flags.iter().all(|x| *x == flags[0]) {
+                    " with the same flags"
+                } else {
+                    " with different flags, only one of them takes effect"
+                }
+            ),
+        });
+    }
+
+    // Flags found only in one entry, or missing only from one, among at least 3 siblings
+    for entries in by_dir.values() {
+        if entries.len() < 3 {
+            continue;
+        }
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (_, flags) in entries.iter() {
+            let mut seen: Vec<&str> = flags.iter().map(|x| x.as_str()).collect();
+            seen.sort_unstable();
+            seen.dedup();
+            for flag in seen {
+                *counts.entry(flag).or_default() += 1;
+            }
+        }
+        for (i, flags) in entries.iter() {
+            let mut extra: Vec<&str> = flags
+                .iter()
+                .map(|x| x.as_str())
+                .filter(|x| counts[x] == 1)
+                .collect();
+            extra.dedup();
+            let mut missing: Vec<&str> = counts
+                .iter()
+                .filter(|(flag, count)| {
+                    **count == entries.len() - 1 && !flags.iter().any(|x| x == *flag)
+                })
+                .map(|(flag, _)| *flag)
+                .collect();
+            missing.sort_unstable();
+            let mut unusual = Vec::new();
+            if !extra.is_empty() {
+                unusual.push(format!("has {}", extra.join(" ")));
+            }
+            if !missing.is_empty() {
+                unusual.push(format!("lacks {}", missing.join(" ")));
+            }
+            if unusual.is_empty() {
+                continue;
+            }
+            let record = &records[*i];
+            findings.push(Finding {
+                severity: Severity::Warning,
+                file: normalize_path(Path::new(&record.directory).join(&record.file))
+                    .to_string_lossy()
+                    .to_string(),
+                message: format!(
+                    "flags unusual among {} siblings: {}",
+                    entries.len() - 1,
+                    unusual.join(", ")
+                ),
+            });
+        }
+    }
+
+    findings
+}
+
+/// Print the findings and return the number of errors
+pub(crate) fn print_findings(findings: &[Finding], num_entries: usize) -> usize {
+    for item in findings.iter() {
+        match item.severity {
+            Severity::Error => println!(
+                "{0}error{0:#}: {1}: {2}",
+                STYLE_RED, item.file, item.message
+            ),
+            Severity::Warning => {
+                println!(
+                    "{0}warning{0:#}: {1}: {2}",
+                    STYLE_YELLOW, item.file, item.message
+                )
+            }
+        }
+    }
+    let errors = findings
+        .iter()
+        .filter(|x| x.severity == Severity::Error)
+        .count();
+    println!(
+        "{} entries checked, {} errors, {} warnings",
+        num_entries,
+        errors,
+        findings.len() - errors
+    );
+    errors
+}
+
+/// Load the entries of a stored generation
+pub(crate) fn read_generation(
+    conn: &Connection,
+    generation: i64,
+) -> anyhow::Result<Vec<CompdbRecord>> {
+    let content = fetch_generation(conn, generation)?;
+    serde_json::from_slice(&content)
+        .context(format!("Failed to deserialize generation {}", generation))
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum DelOpt {
+    Generations(Vec<i64>),
+    Oldest(i64),
+    Newest(i64),
+    All,
+}
+
+/// Delete a compilation database generation from the store
+pub(crate) fn remove_generation(conn: &Connection, opt: DelOpt) -> anyhow::Result<usize> {
+    let rows = match opt {
+        DelOpt::Generations(v) => conn.execute(
+            format!(
+                "DELETE FROM compdbs WHERE generation IN ({})",
+                v.iter().map(|_| "?").collect::<Vec<&str>>().join(", ")
+            )
+            .as_str(),
+            params_from_iter(v.iter()),
+        )?,
+        DelOpt::All => conn.execute("DELETE FROM compdbs", ())?,
+        DelOpt::Newest(n) => conn.execute(
+            "DELETE FROM compdbs WHERE generation IN (SELECT generation FROM compdbs ORDER BY generation DESC LIMIT ?1)",
+            [n]
+        )?,
+        DelOpt::Oldest(n) => conn.execute("DELETE FROM compdbs WHERE generation in (SELECT generation FROM compdbs ORDER BY generation ASC LIMIT ?1)", [n])?
+    };
+    prune_store(conn)?;
+    conn.execute("VACUUM", ())?;
+    Ok(rows)
+}
+
+/// Declarative retention of generations, unset limits are not enforced
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RetentionPolicy {
+    /// Number of newest generations to keep per branch and target
+    pub(crate) keep_per_target: Option<usize>,
+    /// Maximum age of generations in days
+    pub(crate) max_age_days: Option<u64>,
+    /// Maximum size in bytes the store takes for generations after deduplication
+    pub(crate) max_size: Option<u64>,
+}
+
+/// Generation removed by gc, with the reason
+#[derive(Clone, Debug)]
+pub(crate) struct Collected {
+    pub(crate) generation: i64,
+    pub(crate) branch: String,
+    pub(crate) target: String,
+    pub(crate) timestamp: i64,
+    pub(crate) reason: String,
+}
+
+/// Remove the generations exceeding the retention policy, the generation in use is always kept.
+/// Generations beyond the per target count or the age go first, then the oldest ones until the
+/// store fits in the size or removing one frees nothing. Nothing is changed in a dry run.
+///
+/// Returns the generations removed along with the stored size before and after.
+pub(crate) fn gc_generations(
+    conn: &mut Connection,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> anyhow::Result<(Vec<Collected>, u64, u64)> {
+    let now = chrono::Utc::now().timestamp();
+    let mut tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let current = get_current_generation(&tx)?;
+    let (_, size_before) = get_store_sizes(&tx)?;
+
+    let mut generations: Vec<Collected> = tx
+        .prepare(
+            "SELECT generation, branch, target, timestamp FROM compdbs ORDER BY generation DESC",
+        )?
+        .query_map([], |row| {
+            Ok(Collected {
+                generation: row.get(0)?,
+                branch: row.get(1)?,
+                target: row.get(2)?,
+                timestamp: row.get(3)?,
+                reason: String::new(),
+            })
+        })?
+        .collect::<Result<Vec<Collected>, _>>()?;
+    generations.retain(|x| Some(x.generation) != current);
+
+    // The generation in use counts as one kept for its branch and target
+    let mut kept: HashMap<(String, String), usize> = HashMap::new();
+    if let Some(generation) = current
+        && let Some(key) = tx
+            .query_row(
+                "SELECT branch, target FROM compdbs WHERE generation=?1",
+                [generation],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+    {
+        kept.insert(key, 1);
+    }
+
+    let mut collected = Vec::new();
+    let mut survivors = Vec::new();
+    for mut item in generations.into_iter() {
+        let count = kept
+            .entry((item.branch.clone(), item.target.clone()))
+            .or_default();
+        if let Some(n) = policy.keep_per_target
+            && *count >= n
+        {
+            item.reason = format!("more than {} for {} on {}", n, item.target, item.branch);
+            collected.push(item);
+            continue;
+        }
+        if let Some(days) = policy.max_age_days
+            && now - item.timestamp > days.saturating_mul(86400).min(i64::MAX as u64) as i64
+        {
+            item.reason = format!("older than {} days", days);
+            collected.push(item);
+            continue;
+        }
+        *count += 1;
+        survivors.push(item);
+    }
+    for item in collected.iter() {
+        tx.execute("DELETE FROM compdbs WHERE generation=?1", [item.generation])?;
+    }
+    prune_store(&tx)?;
+
+    // Entries shared with the generations kept stay in the store, so the removal stops at the
+    // first generation whose removal frees nothing
+    if let Some(max_size) = policy.max_size {
+        let mut size = get_store_sizes(&tx)?.1;
+        while size > max_size
+            && let Some(mut item) = survivors.pop()
+        {
+            let mut sp = tx.savepoint()?;
+            sp.execute("DELETE FROM compdbs WHERE generation=?1", [item.generation])?;
+            prune_store(&sp)?;
+            let (_, shrunk) = get_store_sizes(&sp)?;
+            if shrunk >= size {
+                sp.rollback()?;
+                break;
+            }
+            sp.commit()?;
+            size = shrunk;
+            item.reason = format!("store larger than {}", utils::human_bytes(max_size));
+            collected.push(item);
+        }
+    }
+
+    let (_, size_after) = get_store_sizes(&tx)?;
+    if dry_run || collected.is_empty() {
+        tx.rollback()?;
+    } else {
+        tx.commit()?;
+        conn.execute("VACUUM", ())?;
+    }
+    collected.sort_by_key(|x| x.generation);
+    Ok((collected, size_before, size_after))
+}
+
+/// Fetch the compilation database of a generation from the store
+fn fetch_generation(conn: &Connection, generation: i64) -> anyhow::Result<Vec<u8>> {
+    let digest: Option<String> = conn
+        .query_row(
+            "SELECT digest FROM compdbs WHERE generation=?1",
+            [generation],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let digest = digest.context(format!("Generation {} not available", generation))?;
+    let entries = load_compdb(conn, &digest)?;
+    Ok(serde_json::to_vec_pretty(&entries)?)
+}
+
+/// Find the generation for the current branch whose revision is closest to the current one,
+/// optionally for the given target only. Generations of unknown distance rank last, ties go to
+/// the newest generation.
+pub(crate) fn find_generation_auto(
+    conn: &Connection,
+    repo_info: &RepoInfo,
+    target: Option<&str>,
+) -> anyhow::Result<i64> {
+    let mut stmt = conn.prepare(
+        "SELECT generation, revision FROM compdbs WHERE branch=?1 AND (?2 IS NULL OR target=?2) ORDER BY generation DESC",
+    )?;
+    let candidates = stmt
+        .query_map(params![repo_info.branch(), target], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<(i64, String)>, _>>()?;
+    if candidates.is_empty() {
+        match target {
+            Some(v) => bail!(
+                "No generation for branch {} and target {}",
+                repo_info.branch(),
+                v
+            ),
+            None => bail!("No generation for branch {}", repo_info.branch()),
+        }
+    }
+
+    let mut distances: HashMap<&str, Option<u64>> = HashMap::new();
+    let mut best: Option<(i64, Option<u64>)> = None;
+    for (generation, revision) in candidates.iter() {
+        let distance = *distances
+            .entry(revision.as_str())
+            .or_insert_with(|| repo_info.distance_to(revision));
+        let better = match (best, distance) {
+            (None, _) => true,
+            (Some((_, None)), Some(_)) => true,
+            (Some((_, Some(x))), Some(y)) => y < x,
+            _ => false,
+        };
+        if better {
+            best = Some((*generation, distance));
+        }
+    }
+    Ok(best.unwrap().0)
+}
+
+const HOOK_MARKER: &str = "# Installed by rua";
+
+/// Install or uninstall the git post-checkout hook switching the generation after a branch switch
+pub(crate) fn install_hook(repo_info: &RepoInfo, uninstall: bool) -> anyhow::Result<()> {
+    if let utils::RepoType::Svn = repo_info.repo_type() {
+        bail!("Hooks are only available for git repos");
+    }
+    let repo = gix::open(repo_info.work_dir())?;
+    // A relative core.hooksPath is relative to the work tree, where git runs the hooks
+    let hooks_dir = match repo.config_snapshot().trusted_path("core.hooksPath") {
+        Some(v) => Path::new(repo_info.work_dir()).join(v.context("Invalid core.hooksPath")?),
+        None => repo.common_dir().join("hooks"),
+    };
+    let hook = hooks_dir.join("post-checkout");
+    let existing = fs::read_to_string(&hook).ok();
+    if existing.as_ref().is_some_and(|x| !x.contains(HOOK_MARKER)) {
+        bail!(
+            "{} exists and was not installed by rua, add `rua compdb use --auto --from-hook` to it by hand",
+            hook.display()
+        );
+    }
+    if uninstall {
+        if existing.is_some() {
+            fs::remove_file(&hook).context(format!("Failed to remove {}", hook.display()))?;
+        }
+        return Ok(());
+    }
+
+    let rua_path = env::current_exe().context("Failed to locate the rua binary")?;
+    if let Some(parent) = hook.parent() {
+        fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+    }
+    fs::write(
+        &hook,
+        format!(
+            "#!/bin/sh\n{}, switches the compilation database after a branch switch\n[ \"$3\" = \"1\" ] || exit 0\n{} compdb use --auto --from-hook || true\n",
+            HOOK_MARKER,
+            utils::shell_quote(&rua_path.to_string_lossy())
+        ),
+    )
+    .context(format!("Failed to write {}", hook.display()))?;
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+pub(crate) fn use_generation(
+    conn: &Connection,
+    generation: i64,
+    path_map: &[(String, String)],
+    flag_rules: &FlagRules,
+) -> anyhow::Result<()> {
+    let pb = progress_bar::no_length().with_style(ProgressStyle::with_template(
+        format!("Switching to generation {}...{{msg}}", generation).as_str(),
+    )?);
+    pb.tick();
+    checkout_generation(conn, generation, path_map, flag_rules)?;
+    set_current_generation(conn, generation)?;
+    pb.finish_with_message("ok");
+    Ok(())
+}
+
+/// Write the compilation database of a generation into the current directory, with the path
+/// mappings and the flag rules applied
+pub(crate) fn checkout_generation(
+    conn: &Connection,
+    generation: i64,
+    path_map: &[(String, String)],
+    flag_rules: &FlagRules,
+) -> anyhow::Result<()> {
+    let compile_commands = fetch_generation(conn, generation)?;
+    fs::write(COMPDB_FILE, compile_commands)?;
+    if !path_map.is_empty() {
+        remap_compdb(path_map)?;
+    }
+    if !flag_rules.is_empty() {
+        filter_compdb_flags(flag_rules)?;
+    }
+    Ok(())
+}
+
+/// Archive the compilation database into store as a new generation, and optionally put it in
+/// use. Both happen in one transaction, so that concurrent runs never take each other's
+/// generation as their own. Returns the generation id.
+pub(crate) fn archive_compdb<P>(
+    conn: &mut Connection,
+    branch: &str,
+    commit_id: &str,
+    target: &str,
+    compdb: P,
+    remark: Option<&str>,
+    use_it: bool,
+) -> anyhow::Result<i64>
+where
+    P: AsRef<Path>,
+{
+    let compdb = compdb.as_ref();
+    let content = fs::read(compdb).context(format!("Failed to read {}", compdb.display()))?;
+    let timestamp = chrono::Utc::now().timestamp();
+    // Take the write lock upfront, a deferred transaction fails without waiting when it can't
+    // upgrade its read lock
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let generation =
+        insert_generation(&tx, branch, commit_id, target, timestamp, &content, remark)?;
+    if use_it {
+        set_current_generation(&tx, generation)?;
+    }
+    tx.commit()?;
+    Ok(generation)
+}
+
+/// Generation carried in a bundle, along with its metadata
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BundledGeneration {
+    branch: String,
+    revision: String,
+    target: String,
+    timestamp: i64,
+    remark: Option<String>,
+    compdb: serde_json::Value,
+}
+
+/// Portable bundle of generations, stored as zstd-compressed JSON
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Bundle {
+    version: u32,
+    work_dir: String,
+    generations: Vec<BundledGeneration>,
+}
+
+const BUNDLE_VERSION: u32 = 1;
+
+/// Export generations from the store into a bundle, returns the number of generations exported
+pub(crate) fn export_generations<P: AsRef<Path>>(
+    conn: &Connection,
+    work_dir: &str,
+    generations: &[i64],
+    output: P,
+) -> anyhow::Result<usize> {
+    let output = output.as_ref();
+    let mut bundle = Bundle {
+        version: BUNDLE_VERSION,
+        work_dir: work_dir.to_string(),
+        generations: Vec::with_capacity(generations.len()),
+    };
+    for generation in generations.iter() {
+        let (branch, revision, target, timestamp, remark) = conn
+            .query_row(
+                "SELECT branch, revision, target, timestamp, remark FROM compdbs WHERE generation=?1",
+                [generation],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)),
+            )
+            .optional()?
+            .context(format!("Generation {} not available", generation))?;
+        let content = fetch_generation(conn, *generation)?;
+        bundle.generations.push(BundledGeneration {
+            branch,
+            revision,
+            target,
+            timestamp,
+            remark,
+            compdb: serde_json::from_slice(&content)
+                .context(format!("Failed to deserialize generation {}", generation))?,
+        });
+    }
+    let compressed = encode_all(serde_json::to_string(&bundle)?.as_bytes(), 0)?;
+    fs::write(output, compressed).context(format!("Failed to write {}", output.display()))?;
+    Ok(bundle.generations.len())
+}
+
+/// Whether a path may start right after the text, i.e. the text ends with a delimiter or an
+/// option taking a path like `-I` or `-isystem`
+fn path_may_start(text: &str) -> bool {
+    let Some(c) = text.chars().next_back() else {
+        return true;
+    };
+    if c.is_whitespace() || "=,:;\"'".contains(c) {
+        return true;
+    }
+    let word = text
+        .rsplit(|x: char| x.is_whitespace() || "=,:;\"'".contains(x))
+        .next()
+        .unwrap_or_default();
+    word.len() > 1
+        && word.starts_with('-')
+        && word[1..]
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
+
+/// Whether a path may end right before the text
+fn path_may_end(text: &str) -> bool {
+    match text.chars().next() {
+        None => true,
+        Some(c) => c == '/' || c.is_whitespace() || "=,:;\"'".contains(c),
+    }
+}
+
+/// Replace the path prefixes wherever they start a path in the text, such as `/work` in
+/// `-I/work/include` or `--sysroot=/work/sysroot`, leaving `/workspace` alone. The first prefix
+/// matching at a position wins, and replaced text is never matched again.
+fn map_paths(text: &str, path_map: &[(String, String)]) -> String {
+    let mut mapped = String::with_capacity(text.len());
+    let mut i = 0;
+    'outer: while i < text.len() {
+        for (from, to) in path_map.iter() {
+            if text[i..].starts_with(from.as_str())
+                && path_may_end(&text[i + from.len()..])
+                && path_may_start(&text[..i])
+            {
+                mapped.push_str(to);
+                i += from.len();
+                continue 'outer;
+            }
+        }
+        let c = text[i..].chars().next().unwrap();
+        mapped.push(c);
+        i += c.len_utf8();
+    }
+    mapped
+}
+
+/// Map the paths of the entries with the prefix mappings, in directory, file and output as well
+/// as in the command or arguments. Returns the number of entries changed.
+fn map_compdb_paths(compdb: &mut serde_json::Value, path_map: &[(String, String)]) -> usize {
+    let Some(entries) = compdb.as_array_mut() else {
+        return 0;
+    };
+    let mut changed = 0;
+    for entry in entries.iter_mut().filter_map(|x| x.as_object_mut()) {
+        let mut dirty = false;
+        let mut map = |v: &mut String| {
+            let mapped = map_paths(v, path_map);
+            if mapped != *v {
+                *v = mapped;
+                dirty = true;
+            }
+        };
+        for key in ["directory", "file", "output", "command"] {
+            if let Some(serde_json::Value::String(v)) = entry.get_mut(key) {
+                map(v);
+            }
+        }
+        if let Some(serde_json::Value::Array(args)) = entry.get_mut("arguments") {
+            for arg in args.iter_mut() {
+                if let serde_json::Value::String(v) = arg {
+                    map(v);
+                }
+            }
+        }
+        if dirty {
+            changed += 1;
+        }
+    }
+    changed
+}
+
+/// Tidy up the path mappings given as (FROM, TO). Trailing slashes are dropped, and longer
+/// prefixes come first to take precedence over the shorter ones containing them.
+pub(crate) fn tidy_path_map<I>(pairs: I) -> anyhow::Result<Vec<(String, String)>>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut path_map = Vec::new();
+    for (from, to) in pairs.into_iter() {
+        let trimmed = from.trim_end_matches('/');
+        if trimmed.is_empty() {
+            bail!(
+                "Invalid path mapping: {}={}, FROM can't be empty or /",
+                from,
+                to
+            );
+        }
+        path_map.push((trimmed.to_string(), to.trim_end_matches('/').to_string()));
+    }
+    path_map.sort_by_key(|(from, _)| cmp::Reverse(from.len()));
+    Ok(path_map)
+}
+
+/// Map the paths of the compilation database in the current directory. Returns the number of
+/// entries changed.
+pub(crate) fn remap_compdb(path_map: &[(String, String)]) -> anyhow::Result<usize> {
+    let compdb = Path::new(COMPDB_FILE);
+    let text =
+        fs::read_to_string(compdb).context(format!("Failed to read {}", compdb.display()))?;
+    let mut entries: serde_json::Value = serde_json::from_str(&text)
+        .context(format!("Failed to deserialize {}", compdb.display()))?;
+    let changed = map_compdb_paths(&mut entries, path_map);
+    if changed > 0 {
+        fs::write(compdb, serde_json::to_string_pretty(&entries)?)?;
+    }
+    Ok(changed)
+}
+
+/// Map the paths of the generations in place. Returns the number of entries changed for each
+/// generation.
+pub(crate) fn rewrite_generations(
+    conn: &mut Connection,
+    generations: &[i64],
+    path_map: &[(String, String)],
+) -> anyhow::Result<Vec<(i64, usize)>> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let mut rewritten = Vec::new();
+    for generation in generations.iter() {
+        let digest: String = tx
+            .query_row(
+                "SELECT digest FROM compdbs WHERE generation=?1",
+                [generation],
+                |row| row.get(0),
+            )
+            .optional()?
+            .context(format!("Generation {} not available", generation))?;
+        let mut entries = serde_json::Value::Array(load_compdb(&tx, &digest)?);
+        let changed = map_compdb_paths(&mut entries, path_map);
+        if changed > 0 {
+            let digest = store_compdb(&tx, &serde_json::to_vec_pretty(&entries)?)?;
+            tx.execute(
+                "UPDATE compdbs SET digest=?1 WHERE generation=?2",
+                params![digest, generation],
+            )?;
+        }
+        rewritten.push((*generation, changed));
+    }
+    prune_store(&tx)?;
+    tx.commit()?;
+    Ok(rewritten)
+}
+
+/// Import the generations of a bundle into the store, optionally rebasing the paths onto the
+/// work dir given. Returns the generations imported.
+pub(crate) fn import_generations<P: AsRef<Path>>(
+    conn: &mut Connection,
+    work_dir: &str,
+    bundle: P,
+    rebase: bool,
+) -> anyhow::Result<Vec<i64>> {
+    let bundle = bundle.as_ref();
+    let compressed = fs::read(bundle).context(format!("Failed to read {}", bundle.display()))?;
+    let content = decode_all(&compressed[..])
+        .context(format!("Failed to decompress {}", bundle.display()))?;
+    let mut bundle: Bundle = serde_json::from_slice(&content)
+        .context(format!("Failed to deserialize {}", bundle.display()))?;
+    if bundle.version > BUNDLE_VERSION {
+        bail!("Unsupported bundle version: {}", bundle.version);
+    }
+
+    // The bundle is imported as a whole or not at all
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let mut imported = Vec::with_capacity(bundle.generations.len());
+    for item in bundle.generations.iter_mut() {
+        if rebase && bundle.work_dir != work_dir {
+            map_compdb_paths(
+                &mut item.compdb,
+                &[(bundle.work_dir.clone(), work_dir.to_string())],
+            );
+        }
+        imported.push(insert_generation(
+            &tx,
+            &item.branch,
+            &item.revision,
+            &item.target,
+            item.timestamp,
+            serde_json::to_string_pretty(&item.compdb)?.as_bytes(),
+            item.remark.as_deref(),
+        )?);
+    }
+    tx.commit()?;
+    Ok(imported)
+}
+
+/// How to settle entries compiling the same file into the same output
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum MergePolicy {
+    /// Keep the entry seen first, the current compilation database comes first
+    FirstWins,
+    /// Keep the entry seen last
+    LastWins,
+    /// Keep every entry with a different command, only identical ones are dropped
+    KeepAll,
+    /// Keep the entry defining the most macros, the first one on ties
+    PreferEntryWithMoreDefines,
+}
+
+impl fmt::Display for MergePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FirstWins => write!(f, "first-wins"),
+            Self::LastWins => write!(f, "last-wins"),
+            Self::KeepAll => write!(f, "keep-all"),
+            Self::PreferEntryWithMoreDefines => write!(f, "prefer-entry-with-more-defines"),
+        }
+    }
+}
+
+/// Outcome of a merge. Duplicates are entries identical to the one kept, conflicts are
+/// entries for the same compilation with a different command.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct MergeSummary {
+    pub(crate) entries: usize,
+    pub(crate) duplicates: usize,
+    pub(crate) conflicts: usize,
+}
+
+impl fmt::Display for MergeSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} entries, {} duplicates dropped, {} conflicts resolved",
+            self.entries, self.duplicates, self.conflicts
+        )
+    }
+}
+
+pub(crate) fn read_compdb(path: &Path) -> anyhow::Result<Vec<CompdbRecord>> {
+    let file = fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let reader = io::BufReader::new(file);
+    serde_json::from_reader(reader).context(format!("Failed to deserialize {}", path.display()))
+}
+
+/// Merge compilation databases into the one in the current directory, settling entries for the
+/// same compilation according to the policy.
+pub(crate) fn merge_compdb<T: AsRef<Path>>(
+    files: Vec<T>,
+    policy: MergePolicy,
+) -> anyhow::Result<MergeSummary> {
+    let compdb = Path::new(COMPDB_FILE);
+    let mut records = Vec::new();
+    if compdb.is_file() {
+        records.extend(read_compdb(compdb)?);
+    }
+    for item in files.iter().map(|x| x.as_ref()) {
+        if !item.is_file() {
+            eprintln!("File {} not found", item.display());
+            continue;
+        }
+        records.extend(read_compdb(item)?);
+    }
+
+    let (merged, summary) = merge_records(records, policy);
+    fs::write(compdb, serde_json::to_string_pretty(&merged)?)?;
+    Ok(summary)
+}
+
+/// Merge the compilation databases of the generations into the one in the current directory,
+/// settling entries for the same compilation according to the policy. Earlier generations come
+/// first.
+pub(crate) fn union_generations(
+    conn: &Connection,
+    generations: &[i64],
+    policy: MergePolicy,
+) -> anyhow::Result<MergeSummary> {
+    let mut records = Vec::new();
+    for generation in generations.iter() {
+        records.extend(read_generation(conn, *generation)?);
+    }
+
+    let (merged, summary) = merge_records(records, policy);
+    fs::write(COMPDB_FILE, serde_json::to_string_pretty(&merged)?)?;
+    Ok(summary)
+}
+
+/// Settle the entries for the same compilation according to the policy, keeping the order they
+/// are first seen in
+fn merge_records(
+    records: Vec<CompdbRecord>,
+    policy: MergePolicy,
+) -> (Vec<CompdbRecord>, MergeSummary) {
+    let mut summary = MergeSummary::default();
+    let mut merged: IndexMap<(PathBuf, PathBuf, Option<PathBuf>), Vec<CompdbRecord>> =
+        IndexMap::new();
+    for record in records.into_iter() {
+        let slot = merged.entry(record.merge_key()).or_default();
+        let Some(kept) = slot.first() else {
+            slot.push(record);
+            continue;
+        };
+        if slot.iter().any(|x| x.command() == record.command()) {
+            summary.duplicates += 1;
+            continue;
+        }
+        summary.conflicts += 1;
+        match policy {
+            MergePolicy::FirstWins => {}
+            MergePolicy::LastWins => slot[0] = record,
+            MergePolicy::KeepAll => slot.push(record),
+            MergePolicy::PreferEntryWithMoreDefines => {
+                if record.num_defines() > kept.num_defines() {
+                    slot[0] = record;
+                }
+            }
+        }
+    }
+    let merged: Vec<CompdbRecord> = merged.into_values().flatten().collect();
+    summary.entries = merged.len();
+    (merged, summary)
+}
+
+/// Depth limit for nested response files, guarding against files including each other
+const RESPONSE_FILE_DEPTH: usize = 8;
+
+/// Replace `@file` arguments with the words of the response file, resolved relative to the
+/// compiling directory. Arguments naming no existing file are kept as they are, like gcc does.
+fn expand_response_files(
+    args: Vec<String>,
+    directory: &Path,
+    depth: usize,
+) -> anyhow::Result<Vec<String>> {
+    let mut expanded = Vec::with_capacity(args.len());
+    for arg in args.into_iter() {
+        let rsp = match arg.strip_prefix('@') {
+            Some(v) if !v.is_empty() => directory.join(v),
+            _ => {
+                expanded.push(arg);
+                continue;
+            }
+        };
+        if !rsp.is_file() {
+            expanded.push(arg);
+            continue;
+        }
+        if depth == 0 {
+            bail!("Response files nested too deeply: {}", rsp.display());
+        }
+        let content =
+            fs::read_to_string(&rsp).context(format!("Failed to read {}", rsp.display()))?;
+        let words = utils::shell_split(&content)
+            .context(format!("Failed to parse response file {}", rsp.display()))?;
+        expanded.extend(expand_response_files(words, directory, depth - 1)?);
+    }
+    Ok(expanded)
+}
+
+/// Rewrite the compilation database in the current directory into the `arguments` form, with
+/// response files expanded and file paths made canonical.
+pub(crate) fn tokenize_compdb() -> anyhow::Result<usize> {
+    let compdb = Path::new(COMPDB_FILE);
+    let text =
+        fs::read_to_string(compdb).context(format!("Failed to read {}", compdb.display()))?;
+    let mut records: Vec<CompdbRecord> = serde_json::from_str(&text)
+        .context(format!("Failed to deserialize {}", compdb.display()))?;
+    for record in records.iter_mut() {
+        let directory = PathBuf::from(&record.directory);
+        let arguments = record
+            .arguments()
+            .context(format!("Failed to split the command for {}", record.file))?;
+        record.compile_command = CommandOrArguments::Arguments {
+            arguments: expand_response_files(arguments, &directory, RESPONSE_FILE_DEPTH)?,
+        };
+        let file = directory.join(&record.file);
+        record.file = fs::canonicalize(&file)
+            .unwrap_or_else(|_| normalize_path(&file))
+            .to_string_lossy()
+            .to_string();
+    }
+    fs::write(compdb, serde_json::to_string_pretty(&records)?)?;
+    Ok(records.len())
+}
+
+/// Extensions of header files to synthesize entries for
+const HEADER_EXTENSIONS: [&str; 4] = ["h", "hh", "hpp", "hxx"];
+
+/// Extensions of C translation units, the others are taken as C++
+const C_EXTENSIONS: [&str; 1] = ["c"];
+
+/// Options of dependency file generation, which make no sense for headers
+const DEPENDENCY_OPTIONS: [&str; 6] = ["-M", "-MM", "-MD", "-MMD", "-MP", "-MG"];
+
+/// Options taking a value which are dropped from the commands for headers
+const HEADER_DROPPED_OPTIONS: [&str; 5] = ["-o", "-MF", "-MT", "-MQ", "-x"];
+
+/// Translation unit of the compilation database, parsed for header synthesis
+struct TranslationUnit {
+    index: usize,
+    directory: PathBuf,
+    file: PathBuf,
+    arguments: Vec<String>,
+    include_dirs: Vec<PathBuf>,
+}
+
+impl TranslationUnit {
+    fn new(index: usize, record: &CompdbRecord) -> Option<Self> {
+        let directory = normalize_path(&record.directory);
+        let file = normalize_path(directory.join(&record.file));
+        let extension = file.extension()?.to_str()?;
+        if HEADER_EXTENSIONS.contains(&extension) {
+            return None;
+        }
+        let arguments = record.arguments().ok()?;
+        let mut include_dirs = Vec::new();
+        let mut iter = arguments.iter();
+        while let Some(arg) = iter.next() {
+            let dir = match arg.as_str() {
+                "-I" | "-iquote" => iter.next().map(|x| x.as_str()),
+                _ => arg
+                    .strip_prefix("-I")
+                    .or_else(|| arg.strip_prefix("-iquote")),
+            };
+            if let Some(dir) = dir.filter(|x| !x.is_empty()) {
+                include_dirs.push(normalize_path(directory.join(dir)));
+            }
+        }
+        Some(TranslationUnit {
+            index,
+            directory,
+            file,
+            arguments,
+            include_dirs,
+        })
+    }
+
+    /// Dependency file produced along with the object by -MD/-MMD, or named by -MF
+    fn dependency_file(&self) -> Option<PathBuf> {
+        let mut output = None;
+        let mut depfile = None;
+        let mut wants_deps = false;
+        let mut iter = self.arguments.iter();
+        while let Some(arg) = iter.next() {
+            if arg == "-o" {
+                output = iter.next().cloned();
+            } else if arg == "-MF" {
+                depfile = iter.next().cloned();
+            } else if let Some(v) = arg.strip_prefix("-MF") {
+                depfile = Some(v.to_string());
+            } else if let Some(v) = arg
+                .strip_prefix("-Wp,-MD,")
+                .or_else(|| arg.strip_prefix("-Wp,-MMD,"))
+            {
+                depfile = Some(v.to_string());
+            } else if arg == "-MD" || arg == "-MMD" {
+                wants_deps = true;
+            } else if let Some(v) = joined_output(arg) {
+                output = Some(v.to_string());
+            }
+        }
+        match (depfile, output) {
+            (Some(v), _) => Some(self.directory.join(v)),
+            (None, Some(v)) if wants_deps => {
+                Some(self.directory.join(Path::new(&v).with_extension("d")))
+            }
+            _ => None,
+        }
+    }
+
+    /// Resolve an include directive the way the compiler does, quoted ones are looked up in the
+    /// directory of the includer first
+    fn resolve_include(&self, includer: &Path, name: &str, quoted: bool) -> Option<PathBuf> {
+        let local = includer.parent().filter(|_| quoted).map(|x| x.join(name));
+        local
+            .into_iter()
+            .chain(self.include_dirs.iter().map(|x| x.join(name)))
+            .map(normalize_path)
+            .find(|x| x.is_file())
+    }
+}
+
+/// Headers listed in a make dependency file
+fn read_dependency_file(path: &Path, directory: &Path) -> Option<Vec<PathBuf>> {
+    let text = fs::read_to_string(path).ok()?.replace("\\\n", " ");
+    let mut deps = Vec::new();
+    for line in text.lines() {
+        let Some((_, prerequisites)) = line.split_once(": ") else {
+            continue;
+        };
+        let mut word = String::new();
+        let mut chars = prerequisites.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&' ') => word.push(chars.next().unwrap()),
+                c if c.is_whitespace() => {
+                    if !word.is_empty() {
+                        deps.push(normalize_path(directory.join(&word)));
+                        word.clear();
+                    }
+                }
+                c => word.push(c),
+            }
+        }
+        if !word.is_empty() {
+            deps.push(normalize_path(directory.join(&word)));
+        }
+    }
+    Some(deps)
+}
+
+/// Include directives of a source file, as (name, quoted)
+fn scan_includes(path: &Path, pattern: &Regex) -> Vec<(String, bool)> {
+    let Ok(content) = fs::read(path) else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&content)
+        .lines()
+        .filter_map(|line| pattern.captures(line))
+        .map(|x| (x[2].to_string(), &x[1] == "\""))
+        .collect()
+}
+
+/// How well a translation unit stands for a header, the higher the better: a unit named after
+/// the header wins, then the one closest to it in the tree, then the one including it directly.
+type IncluderRank = (bool, usize, bool);
+
+/// Rank the translation unit as the includer of the header
+fn rank_includer(header: &Path, unit: &TranslationUnit, direct: bool) -> IncluderRank {
+    let same_stem = header.file_stem() == unit.file.file_stem();
+    let closeness = header
+        .parent()
+        .unwrap_or(header)
+        .components()
+        .zip(unit.file.parent().unwrap_or(&unit.file).components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    (same_stem, closeness, direct)
+}
+
+/// Compile command for the header, derived from that of the including translation unit
+fn header_arguments(unit: &TranslationUnit, header: &Path) -> Vec<String> {
+    let language = match unit.file.extension().and_then(|x| x.to_str()) {
+        Some(v) if C_EXTENSIONS.contains(&v) => "c-header",
+        _ => "c++-header",
+    };
+    let mut arguments = Vec::with_capacity(unit.arguments.len() + 2);
+    let mut iter = unit.arguments.iter();
+    if let Some(compiler) = iter.next() {
+        arguments.push(compiler.clone());
+    }
+    arguments.push("-x".to_string());
+    arguments.push(language.to_string());
+    while let Some(arg) = iter.next() {
+        if HEADER_DROPPED_OPTIONS.contains(&arg.as_str()) {
+            iter.next();
+        } else if DEPENDENCY_OPTIONS.contains(&arg.as_str())
+            || arg.starts_with("-Wp,-MD,")
+            || arg.starts_with("-Wp,-MMD,")
+            || HEADER_DROPPED_OPTIONS.iter().any(|x| arg.starts_with(x))
+        {
+            continue;
+        } else if normalize_path(unit.directory.join(arg)) == unit.file {
+            arguments.push(header.to_string_lossy().to_string());
+        } else {
+            arguments.push(arg.clone());
+        }
+    }
+    arguments
+}
+
+/// Add entries for the headers under the include dirs of the compilation database in the current
+/// directory, each with the flags of the translation unit which stands for it best. Includers
+/// are found in the dependency files left by the build, or else by scanning include directives.
+///
+/// Returns the number of entries added.
+pub(crate) fn synthesize_header_entries() -> anyhow::Result<usize> {
+    let compdb = Path::new(COMPDB_FILE);
+    let mut records = read_compdb(compdb)?;
+    let added = add_header_entries(&mut records);
+    if added > 0 {
+        fs::write(compdb, serde_json::to_string_pretty(&records)?)?;
+    }
+    Ok(added)
+}
+
+/// Append the entries for the headers not listed yet, see [`synthesize_header_entries`]
+fn add_header_entries(records: &mut Vec<CompdbRecord>) -> usize {
+    let units: Vec<TranslationUnit> = records
+        .iter()
+        .enumerate()
+        .filter_map(|(i, x)| TranslationUnit::new(i, x))
+        .collect();
+    let known: HashSet<PathBuf> = records
+        .iter()
+        .map(|x| normalize_path(Path::new(&x.directory).join(&x.file)))
+        .collect();
+
+    // Headers under the include dirs referenced
+    let mut headers: IndexMap<PathBuf, Option<(usize, IncluderRank)>> = IndexMap::new();
+    let mut include_dirs: Vec<&PathBuf> =
+        units.iter().flat_map(|x| x.include_dirs.iter()).collect();
+    include_dirs.sort();
+    include_dirs.dedup();
+    for dir in include_dirs.into_iter().filter(|x| x.is_dir()) {
+        for entry in walkdir::WalkDir::new(dir)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|x| x.ok())
+            .filter(|x| x.file_type().is_file())
+        {
+            let path = normalize_path(entry.path());
+            if path
+                .extension()
+                .and_then(|x| x.to_str())
+                .is_some_and(|x| HEADER_EXTENSIONS.contains(&x))
+                && !known.contains(&path)
+            {
+                headers.entry(path).or_insert(None);
+            }
+        }
+    }
+    if headers.is_empty() {
+        return 0;
+    }
+
+    let mut offer = |header: &Path, unit: &TranslationUnit, direct: bool| {
+        if let Some(best) = headers.get_mut(header) {
+            let rank = rank_includer(header, unit, direct);
+            if best.as_ref().is_none_or(|(_, x)| rank > *x) {
+                *best = Some((unit.index, rank));
+            }
+        }
+    };
+
+    // Includers of the headers, the whole closure comes from dependency files, while scanning
+    // yields the direct includes only
+    let pattern = Regex::new(r#"^\s*#\s*include\s*(["<])([^">]+)[">]"#).unwrap();
+    for unit in units.iter() {
+        match unit
+            .dependency_file()
+            .and_then(|x| read_dependency_file(&x, &unit.directory))
+        {
+            Some(deps) => {
+                let direct: Vec<PathBuf> = scan_includes(&unit.file, &pattern)
+                    .into_iter()
+                    .filter_map(|(name, quoted)| unit.resolve_include(&unit.file, &name, quoted))
+                    .collect();
+                for dep in deps.iter() {
+                    offer(dep, unit, direct.contains(dep));
+                }
+            }
+            None => {
+                for (name, quoted) in scan_includes(&unit.file, &pattern) {
+                    if let Some(header) = unit.resolve_include(&unit.file, &name, quoted) {
+                        offer(&header, unit, true);
+                    }
+                }
+            }
+        }
+    }
+
+    // Headers only included by other headers inherit the includer of those
+    let units_by_index: HashMap<usize, &TranslationUnit> =
+        units.iter().map(|x| (x.index, x)).collect();
+    let mut queue: Vec<(PathBuf, usize)> = headers
+        .iter()
+        .filter_map(|(k, v)| v.map(|(i, _)| (k.clone(), i)))
+        .collect();
+    while let Some((header, index)) = queue.pop() {
+        let unit = units_by_index[&index];
+        for (name, quoted) in scan_includes(&header, &pattern) {
+            if let Some(nested) = unit.resolve_include(&header, &name, quoted)
+                && let Some(best) = headers.get_mut(&nested)
+                && best.is_none()
+            {
+                *best = Some((index, rank_includer(&nested, unit, false)));
+                queue.push((nested, index));
+            }
+        }
+    }
+
+    let mut added = 0;
+    for (header, best) in headers.iter() {
+        let Some((index, _)) = best else {
+            continue;
+        };
+        let unit = units_by_index[index];
+        let arguments = header_arguments(unit, header);
+        let compile_command = match records[*index].compile_command {
+            CommandOrArguments::Command { .. } => CommandOrArguments::Command {
+                command: arguments
+                    .iter()
+                    .map(|x| utils::shell_quote(x))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            },
+            CommandOrArguments::Arguments { .. } => CommandOrArguments::Arguments { arguments },
+        };
+        records.push(CompdbRecord {
+            directory: unit.directory.to_string_lossy().to_string(),
+            compile_command,
+            file: header.to_string_lossy().to_string(),
+            output: None,
+        });
+        added += 1;
+    }
+    added
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum)]
+pub(crate) enum SystemIncludes {
+    /// Add the flags to each entry
+    Flags,
+    /// Write the flags into a .clangd fragment
+    Clangd,
+}
+
+/// Predefined macros which clang defines on its own, never passed on
+const CLANG_OWNED_MACROS: [&str; 14] = [
+    "__STDC__",
+    "__STDC_VERSION__",
+    "__STDC_HOSTED__",
+    "__STDC_UTF_16__",
+    "__STDC_UTF_32__",
+    "__cplusplus",
+    "__FILE__",
+    "__LINE__",
+    "__DATE__",
+    "__TIME__",
+    "__TIMESTAMP__",
+    "__COUNTER__",
+    "__BASE_FILE__",
+    "__INCLUDE_LEVEL__",
+];
+
+/// First line of the .clangd fragments written by rua, which are safe to overwrite
+const CLANGD_MARKER: &str = "# Generated by rua compdb gen --system-includes clangd";
+
+/// Builtin setup of a cross compiler, discovered by running it
+#[derive(Clone, Debug, Default)]
+struct Toolchain {
+    target: Option<String>,
+    include_dirs: Vec<String>,
+    macros: Vec<(String, String)>,
+}
+
+impl Toolchain {
+    /// Flags making clang behave like the compiler
+    fn flags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if let Some(v) = self.target.as_ref() {
+            flags.push(format!("--target={}", v));
+        }
+        for dir in self.include_dirs.iter() {
+            flags.push("-isystem".to_string());
+            flags.push(dir.clone());
+        }
+        for (name, value) in self.macros.iter() {
+            if value.is_empty() {
+                flags.push(format!("-D{}", name));
+            } else {
+                flags.push(format!("-D{}={}", name, value));
+            }
+        }
+        flags
+    }
+}
+
+/// Log of the commands probing the toolchains
+const PROBE_LOG_PATH: &str = ".rua/probe.log";
+
+/// Run the command by the runner under the directory, returning stdout and stderr combined. The
+/// probe log goes under the repo root, wherever the current directory is.
+fn run_probe(
+    runner: &Runner,
+    repo_root: &Path,
+    directory: &Path,
+    command: &str,
+) -> anyhow::Result<String> {
+    // The directory may only exist in the container, so change to it in the command
+    let command = format!(
+        "cd {} && {}",
+        utils::shell_quote(&directory.to_string_lossy()),
+        command
+    );
+    let probe_log = repo_root.join(PROBE_LOG_PATH);
+    let status = runner
+        .command(&command, repo_root, &probe_log)?
+        .status()
+        .context(format!("Failed to run {}", command))?;
+    let output = fs::read_to_string(&probe_log).unwrap_or_default();
+    fs::remove_file(&probe_log).ok();
+    if !status.success() {
+        bail!("Command `{}` failed: {}", command, output.trim());
+    }
+    Ok(output)
+}
+
+/// Object-like macros in the output of `-dM -E`, as (name, value)
+fn parse_macros(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .filter_map(|x| x.strip_prefix("#define "))
+        .map(|x| x.split_once(' ').unwrap_or((x, "")))
+        .filter(|(name, _)| !name.contains('(') && !CLANG_OWNED_MACROS.contains(name))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Discover the builtin include dirs, target triple and predefined macros of the compiler.
+/// Macros which the host clang predefines the same way for the target are left out.
+fn probe_toolchain(
+    runner: &Runner,
+    repo_root: &Path,
+    compiler: &str,
+    language: &str,
+    directory: &Path,
+) -> anyhow::Result<Toolchain> {
+    let compiler = utils::shell_quote(compiler);
+    let verbose = run_probe(
+        runner,
+        repo_root,
+        directory,
+        &format!("{} -E -v -x {} /dev/null", compiler, language),
+    )?;
+    let mut include_dirs = Vec::new();
+    let mut in_list = false;
+    for line in verbose.lines() {
+        if line.starts_with("#include ") && line.ends_with("search starts here:") {
+            in_list = true;
+        } else if line.starts_with("End of search list.") {
+            break;
+        } else if in_list {
+            let dir = line.trim().trim_end_matches(" (framework directory)");
+            include_dirs.push(normalize_path(dir).to_string_lossy().to_string());
+        }
+    }
+
+    let target = run_probe(
+        runner,
+        repo_root,
+        directory,
+        &format!("{} -dumpmachine", compiler),
+    )
+    .ok()
+    .map(|x| x.trim().to_string())
+    .filter(|x| !x.is_empty() && !x.contains(char::is_whitespace));
+
+    let defines = run_probe(
+        runner,
+        repo_root,
+        directory,
+        &format!("{} -dM -E -x {} /dev/null", compiler, language),
+    )?;
+    let mut macros = parse_macros(&defines);
+    let mut clang = Command::new("clang");
+    if let Some(v) = target.as_ref() {
+        clang.arg(format!("--target={}", v));
+    }
+    if let Ok(output) = clang
+        .args(["-dM", "-E", "-x", language, "/dev/null"])
+        .output()
+        && output.status.success()
+    {
+        let builtin: HashSet<(String, String)> =
+            parse_macros(&String::from_utf8_lossy(&output.stdout))
+                .into_iter()
+                .collect();
+        macros.retain(|x| !builtin.contains(x));
+    }
+
+    macros.sort();
+    Ok(Toolchain {
+        target,
+        include_dirs,
+        macros,
+    })
+}
+
+/// Discover the builtin setup of each compiler in the compilation database in the current
+/// directory by running it with the runner, and pass it on explicitly for clang-based tooling,
+/// either in the entries or in a .clangd fragment. Paths in the fragment are mapped by the path
+/// mappings, while those in the entries are left for the caller to map.
+///
+/// Returns the number of toolchains discovered.
+pub(crate) fn add_system_includes(
+    mode: SystemIncludes,
+    path_map: &[(String, String)],
+    runner: &Runner,
+    repo_root: &Path,
+) -> anyhow::Result<usize> {
+    let compdb = Path::new(COMPDB_FILE);
+    let mut records = read_compdb(compdb)?;
+
+    // Compilers are told apart by language too, as the include dirs of C++ differ
+    let mut toolchains: IndexMap<(String, &str), Option<Toolchain>> = IndexMap::new();
+    let mut keys = Vec::with_capacity(records.len());
+    for record in records.iter() {
+        let directory = normalize_path(&record.directory);
+        let Some(compiler) = record.arguments().ok().and_then(|x| x.first().cloned()) else {
+            keys.push(None);
+            continue;
+        };
+        let compiler = if compiler.contains('/') {
+            normalize_path(directory.join(&compiler))
+                .to_string_lossy()
+                .to_string()
+        } else {
+            compiler
+        };
+        let language = match Path::new(&record.file).extension().and_then(|x| x.to_str()) {
+            Some(v) if C_EXTENSIONS.contains(&v) => "c",
+            _ => "c++",
+        };
+        let key = (compiler, language);
+        if !toolchains.contains_key(&key) {
+            let toolchain = match probe_toolchain(runner, repo_root, &key.0, language, &directory) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    eprintln!("Warning: Failed to probe {}, skipped: {}", key.0, e);
+                    None
+                }
+            };
+            toolchains.insert(key.clone(), toolchain);
+        }
+        keys.push(Some(toolchains.get_index_of(&key).unwrap()));
+    }
+    let flags: Vec<Option<Vec<String>>> = toolchains
+        .values()
+        .map(|x| x.as_ref().map(|y| y.flags()))
+        .collect();
+    let discovered = flags.iter().filter(|x| x.is_some()).count();
+
+    match mode {
+        SystemIncludes::Flags => {
+            for (record, key) in records.iter_mut().zip(keys.iter()) {
+                let Some(extra) = key.and_then(|x| flags[x].as_ref()) else {
+                    continue;
+                };
+                match &mut record.compile_command {
+                    CommandOrArguments::Arguments { arguments } => {
+                        arguments.splice(1..1, extra.iter().cloned());
+                    }
+                    CommandOrArguments::Command { command } => {
+                        let mut arguments = utils::shell_split(command)?;
+                        arguments.splice(1..1, extra.iter().cloned());
+                        *command = arguments
+                            .iter()
+                            .map(|x| utils::shell_quote(x))
+                            .collect::<Vec<String>>()
+                            .join(" ");
+                    }
+                }
+            }
+            fs::write(compdb, serde_json::to_string_pretty(&records)?)?;
+        }
+        SystemIncludes::Clangd => {
+            let clangd = Path::new(".clangd");
+            if clangd.is_file()
+                && !fs::read_to_string(clangd)
+                    .unwrap_or_default()
+                    .starts_with(CLANGD_MARKER)
+            {
+                eprintln!("Warning: .clangd exists and is not written by rua, skipped");
+                return Ok(discovered);
+            }
+
+            // Files of the same extension in a directory go to the toolchain compiling most of
+            // them. The toolchain compiling most files overall takes the rest, headers included.
+            let cwd = env::current_dir()?;
+            let mut votes: IndexMap<(PathBuf, String), HashMap<usize, usize>> = IndexMap::new();
+            let mut totals: HashMap<usize, usize> = HashMap::new();
+            for (record, key) in records.iter().zip(keys.iter()) {
+                let Some(key) = key.filter(|x| flags[*x].is_some()) else {
+                    continue;
+                };
+                let file = normalize_path(map_paths(
+                    &Path::new(&record.directory)
+                        .join(&record.file)
+                        .to_string_lossy(),
+                    path_map,
+                ));
+                if let (Some(dir), Some(extension)) = (
+                    file.parent().and_then(|x| x.strip_prefix(&cwd).ok()),
+                    file.extension().and_then(|x| x.to_str()),
+                ) {
+                    *votes
+                        .entry((dir.to_path_buf(), extension.to_string()))
+                        .or_default()
+                        .entry(key)
+                        .or_default() += 1;
+                }
+                *totals.entry(key).or_default() += 1;
+            }
+            let Some(dominant) = totals
+                .iter()
+                .max_by_key(|(k, n)| (**n, cmp::Reverse(**k)))
+                .map(|(k, _)| *k)
+            else {
+                return Ok(discovered);
+            };
+            let mut patterns: IndexMap<usize, Vec<String>> = IndexMap::new();
+            for ((dir, extension), counts) in votes.into_iter() {
+                if let Some((key, _)) = counts
+                    .into_iter()
+                    .max_by_key(|(k, n)| (*n, cmp::Reverse(*k)))
+                    .filter(|(k, _)| *k != dominant)
+                {
+                    let dir = dir.to_string_lossy();
+                    let prefix = if dir.is_empty() {
+                        String::new()
+                    } else {
+                        format!("{}/", regex::escape(&dir))
+                    };
+                    patterns.entry(key).or_default().push(format!(
+                        "{}[^/]*\\.{}",
+                        prefix,
+                        regex::escape(extension.as_str())
+                    ));
+                }
+            }
+
+            let quote = |x: &String| serde_json::to_string(x).unwrap();
+            let add = |key: usize| -> String {
+                flags[key]
+                    .iter()
+                    .flatten()
+                    .map(|x| quote(&map_paths(x, path_map)))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            };
+            let mut fragments = Vec::new();
+            let others: Vec<String> = patterns.values().flatten().map(quote).collect();
+            fragments.push(format!(
+                "{}CompileFlags:\n  Add: [{}]\n",
+                if others.is_empty() {
+                    String::new()
+                } else {
+                    format!("If:\n  PathExclude: [{}]\n", others.join(", "))
+                },
+                add(dominant)
+            ));
+            for (key, list) in patterns.iter() {
+                fragments.push(format!(
+                    "If:\n  PathMatch: [{}]\nCompileFlags:\n  Add: [{}]\n",
+                    list.iter().map(quote).collect::<Vec<String>>().join(", "),
+                    add(*key)
+                ));
+            }
+            fs::write(
+                clangd,
+                format!("{}\n{}", CLANGD_MARKER, fragments.join("---\n")),
+            )?;
+        }
+    }
+    Ok(discovered)
+}
+
+/// Rules rewriting the flags of compile commands, such as dropping GCC-only flags which clang
+/// rejects. They are applied to the compilation database in use only, the store keeps the
+/// original commands.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FlagRules {
+    remove: Vec<Regex>,
+    replace: Vec<(Regex, String)>,
+    append: Vec<String>,
+}
+
+impl FlagRules {
+    pub(crate) fn new(
+        remove: &[String],
+        replace: &IndexMap<String, String>,
+        append: &[String],
+    ) -> anyhow::Result<Self> {
+        let compile = |x: &String| Regex::new(x).context(format!("Invalid flag pattern: {}", x));
+        Ok(FlagRules {
+            remove: remove.iter().map(compile).collect::<Result<_, _>>()?,
+            replace: replace
+                .iter()
+                .map(|(k, v)| Ok((compile(k)?, v.clone())))
+                .collect::<anyhow::Result<_>>()?,
+            append: append.to_vec(),
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.remove.is_empty() && self.replace.is_empty() && self.append.is_empty()
+    }
+
+    /// Rewrite the arguments of a compile command, the compiler is left alone. Flags removed
+    /// are never replaced, the first replace rule matching a flag wins, and a flag replaced
+    /// with nothing is dropped.
+    fn apply(&self, arguments: &[String]) -> Vec<String> {
+        let mut rewritten = Vec::with_capacity(arguments.len() + self.append.len());
+        let mut iter = arguments.iter();
+        if let Some(compiler) = iter.next() {
+            rewritten.push(compiler.clone());
+        }
+        for arg in iter {
+            if self.remove.iter().any(|x| x.is_match(arg)) {
+                continue;
+            }
+            let arg = match self.replace.iter().find(|(x, _)| x.is_match(arg)) {
+                Some((pattern, replacement)) => {
+                    pattern.replace(arg, replacement.as_str()).to_string()
+                }
+                None => arg.clone(),
+            };
+            if !arg.is_empty() {
+                rewritten.push(arg);
+            }
+        }
+        for flag in self.append.iter() {
+            if !rewritten.contains(flag) {
+                rewritten.push(flag.clone());
+            }
+        }
+        rewritten
+    }
+}
+
+/// Apply the flag rules to the compilation database in the current directory. Returns the
+/// number of entries changed.
+pub(crate) fn filter_compdb_flags(rules: &FlagRules) -> anyhow::Result<usize> {
+    let compdb = Path::new(COMPDB_FILE);
+    let mut records = read_compdb(compdb)?;
+    let mut changed = 0;
+    for record in records.iter_mut() {
+        let arguments = record
+            .arguments()
+            .context(format!("Failed to split the command for {}", record.file))?;
+        let rewritten = rules.apply(&arguments);
+        if rewritten == arguments {
+            continue;
+        }
+        record.compile_command = match record.compile_command {
+            CommandOrArguments::Command { .. } => CommandOrArguments::Command {
+                command: rewritten
+                    .iter()
+                    .map(|x| utils::shell_quote(x))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            },
+            CommandOrArguments::Arguments { .. } => CommandOrArguments::Arguments {
+                arguments: rewritten,
+            },
+        };
+        changed += 1;
+    }
+    if changed > 0 {
+        let content = serde_json::to_string_pretty(&records)?;
+        fs::write(compdb, &content)?;
+        // Remember what was written, so that only this very file is taken for a filtered one
+        let marker = Path::new(FILTERED_DIGEST_PATH);
+        if let Some(parent) = marker.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(marker, sha256_hex(content.as_bytes()))?;
+    }
+    Ok(changed)
+}
+
+/// Whether the compilation database is the one last written by the flag rules, by the digest
+/// recorded along with it
+fn is_filtered_checkout(compdb: &Path, marker: &Path) -> bool {
+    let (Ok(content), Ok(digest)) = (fs::read(compdb), fs::read_to_string(marker)) else {
+        return false;
+    };
+    sha256_hex(&content) == digest.trim()
+}
+
+/// Put the original commands of the current generation back into the current directory, when
+/// the compilation database there is still the one rewritten by the flag rules. A database
+/// changed or replaced since is left as it is. Returns whether the original was put back.
+pub(crate) fn checkout_unfiltered(
+    conn: &Connection,
+    path_map: &[(String, String)],
+) -> anyhow::Result<bool> {
+    let marker = Path::new(FILTERED_DIGEST_PATH);
+    if !is_filtered_checkout(Path::new(COMPDB_FILE), marker) {
+        return Ok(false);
+    }
+    let Some(current) = get_current_generation(conn)? else {
+        return Ok(false);
+    };
+    checkout_generation(conn, current, path_map, &FlagRules::default())?;
+    fs::remove_file(marker)?;
+    Ok(true)
+}
+
+/// Remark a compilation database generation
+///
+/// Returns the number of affected rows, non-zero on success, zero on failure
+pub(crate) fn remark_generation(
+    conn: &Connection,
+    generation_id: i64,
+    remark: &str,
+) -> anyhow::Result<usize> {
     let rows = conn.execute(
         "UPDATE compdbs SET remark = ?1 WHERE generation = ?2",
         params![remark, generation_id],
@@ -898,15 +4467,26 @@ pub(crate) fn remark_generation(
     Ok(rows)
 }
 
-/// Get the most recent compilation database which equips with the biggest generation id
-pub(crate) fn get_biggest_generation(conn: &Connection) -> anyhow::Result<Option<i64>> {
-    let generation = conn.query_row(
-        "SELECT generation FROM compdbs ORDER BY generation DESC LIMIT 1",
-        (),
-        |row| row.get(0),
-    )?;
+/// Get the number of entries of the most recent generation for the target
+pub(crate) fn get_last_entries(conn: &Connection, target: &str) -> anyhow::Result<Option<usize>> {
+    let entries: Option<i64> = conn
+        .query_row(
+            "SELECT m.entries FROM compdbs c JOIN manifests m ON c.digest=m.digest WHERE c.target=?1 ORDER BY c.generation DESC LIMIT 1",
+            [target],
+            |row| row.get(0),
+        )
+        .optional()?;
 
-    Ok(generation)
+    Ok(entries.map(|x| x as usize))
+}
+
+/// Get the ids of all generations in store, in ascending order
+pub(crate) fn get_all_generations(conn: &Connection) -> anyhow::Result<Vec<i64>> {
+    let generations = conn
+        .prepare("SELECT generation FROM compdbs ORDER BY generation")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<i64>, _>>()?;
+    Ok(generations)
 }
 
 /// Set the currently used compdb to the specified generation id
@@ -930,3 +4510,734 @@ pub(crate) fn get_current_generation(conn: &Connection) -> anyhow::Result<Option
         .optional()?;
     Ok(generation)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shim_log_records_compiles_only() {
+        let log = [
+            r#"{"directory":"/work/src","arguments":["gcc","-c","-o","a.o","a.c","-DX=1"]}"#,
+            r#"{"directory":"/work/src","arguments":["gcc","-o","prog","a.o","b.o"]}"#,
+            "",
+            r#"{"directory":"/work","arguments":["gcc","-c","src/b.c","../lib/c.c","-I","my dir"]}"#,
+        ]
+        .join("\n");
+        let records = parse_shim_log(&log).unwrap();
+        let files: Vec<&str> = records.iter().map(|x| x.file.as_str()).collect();
+        assert_eq!(files, ["/work/src/a.c", "/work/src/b.c", "/lib/c.c"]);
+        assert_eq!(records[0].command(), "gcc -c -o a.o a.c -DX=1");
+        assert_eq!(
+            records[1].command(),
+            "gcc -c src/b.c ../lib/c.c -I 'my dir'"
+        );
+    }
+
+    #[test]
+    fn shim_log_skips_output_named_like_source() {
+        let log = r#"{"directory":"/w","arguments":["gcc","-c","-o","gen.c","x.c"]}"#;
+        let records = parse_shim_log(log).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].file, "/w/x.c");
+    }
+
+    #[test]
+    fn shim_log_rejects_garbage() {
+        assert!(parse_shim_log("not json").is_err());
+    }
+
+    #[test]
+    fn response_files_expand_recursively() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.rsp"), "-DA=1 @b.rsp '-DS=x y'").unwrap();
+        fs::write(dir.path().join("b.rsp"), "-Iinc\n-DB").unwrap();
+        let args = ["gcc", "@a.rsp", "@missing.rsp", "@", "-c", "x.c"]
+            .map(String::from)
+            .to_vec();
+        let expanded = expand_response_files(args, dir.path(), RESPONSE_FILE_DEPTH).unwrap();
+        assert_eq!(
+            expanded,
+            [
+                "gcc",
+                "-DA=1",
+                "-Iinc",
+                "-DB",
+                "-DS=x y",
+                "@missing.rsp",
+                "@",
+                "-c",
+                "x.c"
+            ]
+        );
+    }
+
+    fn record(directory: &str, command: &str, file: &str) -> CompdbRecord {
+        CompdbRecord {
+            directory: directory.to_string(),
+            compile_command: CommandOrArguments::Command {
+                command: command.to_string(),
+            },
+            file: file.to_string(),
+            output: None,
+        }
+    }
+
+    fn merged_commands(records: Vec<CompdbRecord>, policy: MergePolicy) -> Vec<String> {
+        let (merged, _) = merge_records(records, policy);
+        merged.iter().map(|x| x.command()).collect()
+    }
+
+    #[test]
+    fn merge_settles_conflicts_by_policy() {
+        let records = || {
+            vec![
+                record("/w", "gcc -c -o a.o a.c -DA", "a.c"),
+                record("/w", "gcc -c -o b.o b.c", "b.c"),
+                record("/w", "gcc -c -o a.o a.c -DA -DB", "a.c"),
+                record("/w", "gcc -c -o a.o a.c", "a.c"),
+            ]
+        };
+        assert_eq!(
+            merged_commands(records(), MergePolicy::FirstWins),
+            ["gcc -c -o a.o a.c -DA", "gcc -c -o b.o b.c"]
+        );
+        assert_eq!(
+            merged_commands(records(), MergePolicy::LastWins),
+            ["gcc -c -o a.o a.c", "gcc -c -o b.o b.c"]
+        );
+        assert_eq!(
+            merged_commands(records(), MergePolicy::PreferEntryWithMoreDefines),
+            ["gcc -c -o a.o a.c -DA -DB", "gcc -c -o b.o b.c"]
+        );
+        assert_eq!(
+            merged_commands(records(), MergePolicy::KeepAll),
+            [
+                "gcc -c -o a.o a.c -DA",
+                "gcc -c -o a.o a.c -DA -DB",
+                "gcc -c -o a.o a.c",
+                "gcc -c -o b.o b.c"
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_drops_identical_entries_under_every_policy() {
+        for policy in MergePolicy::value_variants() {
+            let records = vec![
+                record("/w", "gcc -c a.c", "a.c"),
+                record("/w", "gcc -c a.c", "a.c"),
+            ];
+            let (merged, summary) = merge_records(records, *policy);
+            assert_eq!(merged.len(), 1);
+            assert_eq!(summary.duplicates, 1);
+            assert_eq!(summary.conflicts, 0);
+        }
+    }
+
+    #[test]
+    fn merge_key_normalizes_paths_and_output() {
+        let records = vec![
+            record("/w/src", "gcc -c -o ../obj/a.o a.c", "a.c"),
+            record("/w/src/", "gcc -c -o../obj/a.o ./a.c -DX", "./a.c"),
+            record("/w", "gcc -c -o obj/a2.o src/a.c", "src/a.c"),
+        ];
+        let (merged, summary) = merge_records(records, MergePolicy::FirstWins);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(summary.conflicts, 1);
+        assert_eq!(summary.entries, 2);
+    }
+
+    #[test]
+    fn merge_key_takes_only_output_options() {
+        assert_eq!(joined_output("-o../obj/a.o"), Some("../obj/a.o"));
+        for arg in [
+            "-o",
+            "-objc",
+            "-objcmt-migrate-all",
+            "-openmp",
+            "-o0",
+            "-ofast",
+        ] {
+            assert_eq!(joined_output(arg), None, "{}", arg);
+        }
+        // A flag looking like -o neither splits compilations of the same output nor merges
+        // compilations of different ones
+        let records = vec![
+            record("/w", "gcc -c -o a.o a.c", "a.c"),
+            record("/w", "gcc -c -o a.o -openmp a.c", "a.c"),
+            record("/w", "gcc -c -o b.o -o0 a.c", "a.c"),
+        ];
+        let (merged, summary) = merge_records(records, MergePolicy::FirstWins);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(summary.conflicts, 1);
+    }
+
+    fn open_test_store(dir: &Path) -> Connection {
+        let store = dir.join("compdb.store");
+        let mut conn = open_store(&store).unwrap();
+        migrate_store(&mut conn, &store).unwrap();
+        conn
+    }
+
+    fn entry(file: &str) -> serde_json::Value {
+        serde_json::json!({
+            "directory": "/w",
+            "command": format!("gcc -c -DSOME_LONGISH_MACRO=1 -Iinclude/dir {}", file),
+            "file": file,
+        })
+    }
+
+    fn insert_entries(conn: &Connection, files: &[&str]) -> i64 {
+        let compdb: Vec<serde_json::Value> = files.iter().map(|x| entry(x)).collect();
+        let content = serde_json::to_vec(&compdb).unwrap();
+        insert_generation(conn, "master", "c0ffee", "t", 0, &content, None).unwrap()
+    }
+
+    #[test]
+    fn migration_stops_at_latest_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("compdb.store");
+        let mut conn = open_test_store(dir.path());
+        assert_eq!(get_schema_version(&conn).unwrap(), MIGRATIONS.len());
+        migrate_store(&mut conn, &store).unwrap();
+        assert_eq!(get_schema_version(&conn).unwrap(), MIGRATIONS.len());
+
+        conn.pragma_update(None, "user_version", (MIGRATIONS.len() + 1) as i64)
+            .unwrap();
+        assert!(migrate_store(&mut conn, &store).is_err());
+    }
+
+    #[test]
+    fn compressed_size_ignores_formatting() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = open_test_store(dir.path());
+        let compdb: Vec<serde_json::Value> = ["a.c", "b.c"].iter().map(|x| entry(x)).collect();
+        let compact = serde_json::to_vec(&compdb).unwrap();
+        let pretty = serde_json::to_vec_pretty(&compdb).unwrap();
+        assert_ne!(compact.len(), pretty.len());
+        let digest = store_compdb(&conn, &compact).unwrap();
+        let stored: i64 = conn
+            .query_row(
+                "SELECT compressed FROM manifests WHERE digest=?1",
+                [&digest],
+                |row| row.get(0),
+            )
+            .unwrap();
+        // Archived as given, and as sized again by the migration
+        assert_eq!(stored, compressed_size(&compdb).unwrap());
+        assert_eq!(
+            stored,
+            compressed_size(&load_compdb(&conn, &digest).unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn prune_repacks_partly_dead_packs() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = open_test_store(dir.path());
+        let files: Vec<String> = (0..50).map(|x| format!("src/f{}.c", x)).collect();
+        let files: Vec<&str> = files.iter().map(|x| x.as_str()).collect();
+        let old = insert_entries(&conn, &files);
+        let kept = insert_entries(&conn, &files[7..9]);
+        let (_, stored_before) = get_store_sizes(&conn).unwrap();
+
+        conn.execute("DELETE FROM compdbs WHERE generation=?1", [old])
+            .unwrap();
+        prune_store(&conn).unwrap();
+        let (_, stored_after) = get_store_sizes(&conn).unwrap();
+        assert!(stored_after < stored_before / 2);
+        let records: i64 = conn
+            .query_row("SELECT COUNT(*) FROM records", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(records, 2);
+        let content = fetch_generation(&conn, kept).unwrap();
+        let entries: Vec<serde_json::Value> = serde_json::from_slice(&content).unwrap();
+        assert_eq!(entries, [entry(files[7]), entry(files[8])]);
+    }
+
+    #[test]
+    fn diff_classifies_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = open_test_store(dir.path());
+        let insert = |commands: &[(&str, &str)]| {
+            let compdb: Vec<serde_json::Value> = commands
+                .iter()
+                .map(|(file, command)| {
+                    serde_json::json!({"directory": "/w", "command": command, "file": file})
+                })
+                .collect();
+            let content = serde_json::to_vec(&compdb).unwrap();
+            insert_generation(&conn, "master", "c0ffee", "t", 0, &content, None).unwrap()
+        };
+        let from = insert(&[
+            ("a.c", "gcc -c -o a.o -DX -I inc a.c"),
+            ("b.c", "gcc -c -o b.o -DX b.c"),
+            ("gone.c", "gcc -c gone.c"),
+        ]);
+        let to = insert(&[
+            ("./a.c", "cc -c -o obj/a.o -DY -I inc /w/a.c"),
+            ("b.c", "gcc -c -DX -ob2.o b.c"),
+            ("new.c", "gcc -c new.c"),
+        ]);
+
+        let diff = diff_generations(&conn, from, to).unwrap();
+        assert_eq!(diff.files_added, ["/w/new.c"]);
+        assert_eq!(diff.files_removed, ["/w/gone.c"]);
+        // The compiler, the output and the file itself are no flags
+        assert_eq!(diff.files_changed.len(), 1);
+        assert_eq!(diff.files_changed[0].file, "/w/a.c");
+        assert_eq!(diff.files_changed[0].flags_added, ["-DY"]);
+        assert_eq!(diff.files_changed[0].flags_removed, ["-DX"]);
+        assert_eq!(diff.files_unchanged, 1);
+    }
+
+    #[test]
+    fn check_reports_missing_files_and_compilers() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join(".rua")).unwrap();
+        fs::write(root.join("a.c"), "").unwrap();
+        let root_str = root.to_str().unwrap();
+        let records = vec![
+            record(root_str, "sh -c -DX a.c", "a.c"),
+            record(root_str, "sh -c -DX gone.c", "gone.c"),
+            record(root_str, "/nonexistent/cc -c -DX a.c", "a.c"),
+            record(&format!("{}/gone", root_str), "sh -c b.c", "b.c"),
+        ];
+
+        let findings = check_compdb(&records, &Runner::Direct, root);
+        let errors: Vec<(String, &str)> = findings
+            .iter()
+            .filter(|x| x.severity == Severity::Error)
+            .map(|x| {
+                let file = Path::new(&x.file).strip_prefix(root).unwrap();
+                (file.to_string_lossy().to_string(), x.message.as_str())
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                ("gone.c".to_string(), "file does not exist"),
+                (
+                    "a.c".to_string(),
+                    "compiler /nonexistent/cc can't be resolved"
+                ),
+                (
+                    "gone/b.c".to_string(),
+                    &format!("directory {}/gone does not exist", root_str)[..]
+                ),
+            ]
+        );
+        // The stale entry left for a.c is ignored by language servers
+        let warnings: Vec<&str> = findings
+            .iter()
+            .filter(|x| x.severity == Severity::Warning)
+            .map(|x| x.message.as_str())
+            .collect();
+        assert_eq!(warnings, ["listed 2 times with the same flags"]);
+    }
+
+    #[test]
+    fn bundle_round_trip_into_fresh_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&dst).unwrap();
+        let conn = open_test_store(&src);
+        let first = insert_entries(&conn, &["a.c", "b.c"]);
+        let content = serde_json::to_vec(&[entry("c.c")]).unwrap();
+        let second =
+            insert_generation(&conn, "dev", "beef", "a-dnv", 42, &content, Some("ok")).unwrap();
+        set_current_generation(&conn, second).unwrap();
+        let bundle = dir.path().join("gens.rcdb");
+        assert_eq!(
+            export_generations(&conn, "/w", &[first, second], &bundle).unwrap(),
+            2
+        );
+
+        let mut fresh = open_test_store(&dst);
+        let own = insert_entries(&fresh, &["x.c"]);
+        set_current_generation(&fresh, own).unwrap();
+        let imported = import_generations(&mut fresh, "/home/me/w", &bundle, true).unwrap();
+        assert_eq!(imported.len(), 2);
+        // Imported ones are only listed, the generation in use stays
+        assert_eq!(get_current_generation(&fresh).unwrap(), Some(own));
+        let (branch, revision, target, timestamp, remark): (String, String, String, i64, String) =
+            fresh
+                .query_row(
+                    "SELECT branch, revision, target, timestamp, remark FROM compdbs WHERE generation=?1",
+                    [imported[1]],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)),
+                )
+                .unwrap();
+        assert_eq!(
+            (
+                branch.as_str(),
+                revision.as_str(),
+                target.as_str(),
+                timestamp,
+                remark.as_str()
+            ),
+            ("dev", "beef", "a-dnv", 42, "ok")
+        );
+        let records = read_generation(&fresh, imported[0]).unwrap();
+        let files: Vec<&str> = records.iter().map(|x| x.file.as_str()).collect();
+        assert_eq!(files, ["a.c", "b.c"]);
+        assert!(records.iter().all(|x| x.directory == "/home/me/w"));
+        assert_eq!(
+            read_generation(&fresh, imported[1]).unwrap()[0].command(),
+            read_generation(&conn, second).unwrap()[0].command()
+        );
+    }
+
+    #[test]
+    fn header_entries_follow_best_includer() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("inc")).unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("inc/foo.h"), "#include <nested.h>\n").unwrap();
+        fs::write(root.join("inc/nested.h"), "").unwrap();
+        fs::write(root.join("inc/listed.h"), "").unwrap();
+        fs::write(root.join("inc/orphan.h"), "").unwrap();
+        fs::write(root.join("src/bar.c"), "#include \"foo.h\"\n").unwrap();
+        fs::write(root.join("src/foo.c"), "#include \"foo.h\"\n").unwrap();
+        let src = root.join("src");
+        let src = src.to_str().unwrap();
+        let mut records = vec![
+            record(src, "gcc -c -DBAR -I ../inc -o bar.o bar.c", "bar.c"),
+            record(src, "gcc -c -DFOO -I ../inc -o foo.o foo.c", "foo.c"),
+            record(
+                src,
+                "gcc -x c-header -I ../inc ../inc/listed.h",
+                "../inc/listed.h",
+            ),
+        ];
+
+        // The includer named alike wins, nested headers inherit it, listed ones are left alone
+        assert_eq!(add_header_entries(&mut records), 2);
+        let added: Vec<(String, String)> = records[3..]
+            .iter()
+            .map(|x| {
+                let file = Path::new(&x.file).strip_prefix(root).unwrap();
+                (file.to_string_lossy().to_string(), x.command())
+            })
+            .collect();
+        let header = |name: &str| root.join("inc").join(name).to_string_lossy().to_string();
+        assert_eq!(
+            added,
+            [
+                (
+                    "inc/foo.h".to_string(),
+                    format!("gcc -x c-header -c -DFOO -I ../inc {}", header("foo.h"))
+                ),
+                (
+                    "inc/nested.h".to_string(),
+                    format!("gcc -x c-header -c -DFOO -I ../inc {}", header("nested.h"))
+                ),
+            ]
+        );
+        assert_eq!(add_header_entries(&mut records), 0);
+    }
+
+    #[test]
+    fn gc_by_size_keeps_generations_freeing_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = open_test_store(dir.path());
+        let files = ["a.c", "b.c", "c.c"];
+        let older = insert_entries(&conn, &files);
+        let old = insert_entries(&conn, &files);
+        let current = insert_entries(&conn, &files);
+        set_current_generation(&conn, current).unwrap();
+        let policy = RetentionPolicy {
+            max_size: Some(1),
+            ..Default::default()
+        };
+
+        let (collected, size_before, size_after) =
+            gc_generations(&mut conn, &policy, false).unwrap();
+        assert!(collected.is_empty());
+        assert_eq!(size_before, size_after);
+        let generations: Vec<i64> = conn
+            .prepare("SELECT generation FROM compdbs ORDER BY generation")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<i64>, _>>()
+            .unwrap();
+        assert_eq!(generations, [older, old, current]);
+    }
+
+    #[test]
+    fn gc_by_size_removes_oldest_generations_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = open_test_store(dir.path());
+        let files: Vec<String> = (0..50).map(|x| format!("src/f{}.c", x)).collect();
+        let files: Vec<&str> = files.iter().map(|x| x.as_str()).collect();
+        let old = insert_entries(&conn, &files[..25]);
+        let newer = insert_entries(&conn, &files[25..]);
+        let current = insert_entries(&conn, &files[..1]);
+        set_current_generation(&conn, current).unwrap();
+        let (_, stored) = get_store_sizes(&conn).unwrap();
+        let policy = RetentionPolicy {
+            max_size: Some(stored - 1),
+            ..Default::default()
+        };
+
+        let (collected, _, size_after) = gc_generations(&mut conn, &policy, false).unwrap();
+        let collected: Vec<i64> = collected.iter().map(|x| x.generation).collect();
+        assert_eq!(collected, [old]);
+        assert!(size_after < stored);
+        assert!(fetch_generation(&conn, newer).is_ok());
+        assert!(fetch_generation(&conn, current).is_ok());
+    }
+
+    #[test]
+    fn gc_by_age_takes_huge_ages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = open_test_store(dir.path());
+        let old = insert_entries(&conn, &["a.c"]);
+        let current = insert_entries(&conn, &["b.c"]);
+        set_current_generation(&conn, current).unwrap();
+        let policy = RetentionPolicy {
+            max_age_days: Some(u64::MAX),
+            ..Default::default()
+        };
+        let (collected, _, _) = gc_generations(&mut conn, &policy, false).unwrap();
+        assert!(collected.is_empty());
+
+        let policy = RetentionPolicy {
+            max_age_days: Some(1),
+            ..Default::default()
+        };
+        let (collected, _, _) = gc_generations(&mut conn, &policy, false).unwrap();
+        let collected: Vec<i64> = collected.iter().map(|x| x.generation).collect();
+        assert_eq!(collected, [old]);
+    }
+
+    #[test]
+    fn since_takes_periods_back_from_now() {
+        let now = chrono::Local::now().timestamp();
+        for (text, secs) in [("12h", 12 * 3600), ("7d", 7 * 86400), ("2w", 14 * 86400)] {
+            let since = parse_since(text).unwrap();
+            assert!((now - secs..now - secs + 5).contains(&since), "{}", text);
+        }
+    }
+
+    #[test]
+    fn since_takes_local_dates_and_times() {
+        let midnight = chrono::Local
+            .with_ymd_and_hms(2025, 3, 1, 0, 0, 0)
+            .earliest()
+            .unwrap()
+            .timestamp();
+        assert_eq!(parse_since("2025-03-01").unwrap(), midnight);
+        assert_eq!(
+            parse_since("2025-03-01T08:00:00").unwrap(),
+            midnight + 8 * 3600
+        );
+    }
+
+    #[test]
+    fn since_rejects_garbage() {
+        for text in [
+            "",
+            "d",
+            "7",
+            "7x",
+            "-7d",
+            "7 d",
+            "2025-13-01",
+            "2025-03-01 08:00:00",
+        ] {
+            assert!(parse_since(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn paths_mapped_at_path_boundaries_only() {
+        let path_map = tidy_path_map([
+            ("/work/".to_string(), "/home/me/work".to_string()),
+            ("/work/sdk".to_string(), "/opt/sdk".to_string()),
+        ])
+        .unwrap();
+        assert_eq!(
+            map_paths(
+                "gcc -I/work/include --sysroot=/work/sdk/sys -c /work/a.c",
+                &path_map
+            ),
+            "gcc -I/home/me/work/include --sysroot=/opt/sdk/sys -c /home/me/work/a.c"
+        );
+        assert_eq!(
+            map_paths("/workspace/a.c x/work/a.c /work", &path_map),
+            "/workspace/a.c x/work/a.c /home/me/work"
+        );
+        // Replaced text is not mapped again
+        let swap = tidy_path_map([
+            ("/a".to_string(), "/b".to_string()),
+            ("/b".to_string(), "/a".to_string()),
+        ])
+        .unwrap();
+        assert_eq!(map_paths("/a/x /b/y", &swap), "/b/x /a/y");
+    }
+
+    #[test]
+    fn compdb_paths_mapped_in_every_field() {
+        let mut compdb = serde_json::json!([
+            {
+                "directory": "/work/src",
+                "command": "gcc -c -o /work/obj/a.o a.c",
+                "file": "/work/src/a.c",
+                "output": "/work/obj/a.o"
+            },
+            {
+                "directory": "/work/src",
+                "arguments": ["gcc", "-I/work/include", "-c", "b.c"],
+                "file": "b.c"
+            },
+            {
+                "directory": "/elsewhere",
+                "arguments": ["gcc", "-c", "c.c"],
+                "file": "c.c"
+            }
+        ]);
+        let path_map = [("/work".to_string(), "/w".to_string())];
+        assert_eq!(map_compdb_paths(&mut compdb, &path_map), 2);
+        assert_eq!(
+            compdb,
+            serde_json::json!([
+                {
+                    "directory": "/w/src",
+                    "command": "gcc -c -o /w/obj/a.o a.c",
+                    "file": "/w/src/a.c",
+                    "output": "/w/obj/a.o"
+                },
+                {
+                    "directory": "/w/src",
+                    "arguments": ["gcc", "-I/w/include", "-c", "b.c"],
+                    "file": "b.c"
+                },
+                {
+                    "directory": "/elsewhere",
+                    "arguments": ["gcc", "-c", "c.c"],
+                    "file": "c.c"
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn path_map_rejects_root() {
+        assert!(tidy_path_map([("/".to_string(), "/x".to_string())]).is_err());
+    }
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn flag_rules_rewrite_all_but_compiler() {
+        let replace = IndexMap::from([
+            ("^-mabi=.*$".to_string(), String::new()),
+            ("^-march=(.*)$".to_string(), "-mcpu=$1".to_string()),
+            ("^-m.*$".to_string(), "-mno-red-zone".to_string()),
+        ]);
+        let rules = FlagRules::new(
+            &["^-fno-tree-".to_string(), "^-mlong-calls$".to_string()],
+            &replace,
+            &["-Wno-unknown-warning-option".to_string(), "-w".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            rules.apply(&words(
+                "-mips-gcc -mlong-calls -c -mabi=64 -march=mips64 -mfoo -fno-tree-vrp -w a.c"
+            )),
+            words("-mips-gcc -c -mcpu=mips64 -mno-red-zone -w a.c -Wno-unknown-warning-option")
+        );
+    }
+
+    #[test]
+    fn flag_rules_default_to_nothing() {
+        let rules = FlagRules::default();
+        assert!(rules.is_empty());
+        let args = words("gcc -c -O2 a.c");
+        assert_eq!(rules.apply(&args), args);
+        assert!(FlagRules::new(&["(".to_string()], &IndexMap::new(), &[]).is_err());
+    }
+
+    #[test]
+    fn filtered_checkout_only_by_its_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let compdb = dir.path().join(COMPDB_FILE);
+        let marker = dir.path().join("compdb.filtered");
+        fs::write(&compdb, "[]").unwrap();
+        assert!(!is_filtered_checkout(&compdb, &marker));
+        fs::write(&marker, sha256_hex(b"[]")).unwrap();
+        assert!(is_filtered_checkout(&compdb, &marker));
+        // Replaced by another database, or edited by hand
+        fs::write(&compdb, "[ ]").unwrap();
+        assert!(!is_filtered_checkout(&compdb, &marker));
+        fs::remove_file(&compdb).unwrap();
+        assert!(!is_filtered_checkout(&compdb, &marker));
+    }
+
+    #[test]
+    fn buildlog_errors_carry_make_directory() {
+        let log = [
+            "make: Entering directory '/w'",
+            "make[1]: Entering directory '/w/src'",
+            "gcc -c a.c",
+            "a.c:1:10: fatal error: x.h: No such file or directory",
+            "make[1]: *** [a.o] Error 1",
+            "make[1]: Leaving directory '/w/src'",
+            "make: *** [all] Error 2",
+            "make: Leaving directory '/w'",
+        ]
+        .join("\n");
+        assert_eq!(
+            summarize_buildlog(&log),
+            [
+                (
+                    Some("/w/src".to_string()),
+                    "a.c:1:10: fatal error: x.h: No such file or directory".to_string()
+                ),
+                (
+                    Some("/w/src".to_string()),
+                    "make[1]: *** [a.o] Error 1".to_string()
+                ),
+                (
+                    Some("/w".to_string()),
+                    "make: *** [all] Error 2".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn buildlog_make_errors_follow_their_level() {
+        // Parallel jobs interleave, the make error goes to the directory of its own level
+        let log = [
+            "make[1]: Entering directory `/w/a'",
+            "make[2]: Entering directory `/w/a/b'",
+            "make[1]: *** [x] Error 1",
+            "make[2]: *** [y] Error 1",
+        ]
+        .join("\n");
+        let dirs: Vec<Option<String>> = summarize_buildlog(&log)
+            .into_iter()
+            .map(|(x, _)| x)
+            .collect();
+        assert_eq!(dirs, [Some("/w/a".to_string()), Some("/w/a/b".to_string())]);
+        assert!(summarize_buildlog("all fine\nno errors here").is_empty());
+    }
+
+    #[test]
+    fn response_files_nested_too_deeply() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("loop.rsp"), "-DX @loop.rsp").unwrap();
+        let args = vec!["@loop.rsp".to_string()];
+        assert!(expand_response_files(args, dir.path(), RESPONSE_FILE_DEPTH).is_err());
+    }
+}
@@ -3,7 +3,7 @@ use std::env;
 use std::fmt;
 use std::fs;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::LazyLock;
 
 use anstyle::{Ansi256Color, Color, Style};
@@ -17,7 +17,7 @@ use rustix::system::uname;
 use serde_json::{Value, json};
 
 use crate::utils;
-use crate::utils::RepoInfo;
+use crate::utils::{RepoInfo, Runner};
 
 bitflags! {
     #[repr(transparent)]
@@ -53,6 +53,7 @@ pub(crate) struct MakeOpts {
     pub(crate) image_server: Option<ImageServer>,
     pub(crate) nostrip_bins: Vec<String>,
     pub(crate) defines: IndexMap<String, String>,
+    pub(crate) runner: Runner,
 }
 
 impl fmt::Display for MakeOpts {
@@ -64,8 +65,9 @@ impl fmt::Display for MakeOpts {
     image_server: {:?},
     nostrip_bins: {:?},
     user_defines: {:?},
+    runner: {:?},
 }}"#,
-            self.flag, self.image_server, self.nostrip_bins, self.defines
+            self.flag, self.image_server, self.nostrip_bins, self.defines, self.runner
         )
     }
 }
@@ -407,7 +409,11 @@ fn compose_compileinfo(
         platform_model: mkinfo.platform_model.clone(),
         make_target,
         make_directory: mkinfo.make_directory.clone(),
-        make_command: format!(r#"hsdocker7 "{} >build.log 2>&1""#, make_comm),
+        make_command: makeopts.runner.command_line(
+            &make_comm,
+            Path::new("."),
+            Path::new("build.log"),
+        ),
     })
 }
 
@@ -427,13 +433,13 @@ pub(crate) fn gen_mkinfo_by_nickname(
 
     // Read and filter products
     let re_nickname = Regex::new(format!(r#"(?i){}$"#, nickname).as_str())?;
-    let product_infos = read_product_registry(&repoinfo)?
+    let product_infos = read_product_registry(repoinfo)?
         .into_iter()
         .filter(|x| re_nickname.is_match(x.long_name.as_str()))
         .collect::<Vec<ProductInfo>>();
 
     // Read and hash makeinfos, allow duplicates
-    let mkinfo_list = read_mkinfo_registry(&repoinfo)?;
+    let mkinfo_list = read_mkinfo_registry(repoinfo)?;
     let mut mkinfo_map = HashMap::with_capacity(256);
     for item in mkinfo_list {
         mkinfo_map
@@ -483,8 +489,8 @@ pub(crate) fn gen_mkinfo_by_target(
         );
     }
 
-    let product_list = read_product_registry(&repoinfo)?;
-    let mkinfo_list = read_mkinfo_registry(&repoinfo)?;
+    let product_list = read_product_registry(repoinfo)?;
+    let mkinfo_list = read_mkinfo_registry(repoinfo)?;
     let re_target =
         Regex::new(format!("(?i)^{}$", target.strip_suffix("-ipv6").unwrap_or(target)).as_str())?;
     let mut compile_infos: Vec<CompileInfo> = Vec::new();
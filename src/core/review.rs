@@ -79,22 +79,22 @@ pub async fn review(options: &ReviewOptions) -> anyhow::Result<()> {
     if let Some(id) = options.review_id {
         comm.args(["-r", &id.to_string()]);
     } else {
-        comm.arg(&format!("--description-file={}", review_template_file));
+        comm.arg(format!("--description-file={}", review_template_file));
     }
 
-    comm.arg(&format!("--repository-url={}", options.repo_url));
+    comm.arg(format!("--repository-url={}", options.repo_url));
 
     // Remote url and diff file
     let status = if let Some(diff_file) = options.diff_file.as_ref() {
-        comm.arg(&format!("--diff-filename={}", diff_file))
+        comm.arg(format!("--diff-filename={}", diff_file))
             .status()?
     } else {
         let diff = match options.repo_type {
             RepoType::Git => {
                 let mut diff_comm = Command::new("git");
                 diff_comm.args(["diff", "--staged"]);
-                if options.files.is_some() {
-                    diff_comm.args(options.files.as_ref().unwrap());
+                if let Some(files) = options.files.as_ref() {
+                    diff_comm.args(files);
                 }
                 let output = diff_comm
                     .output()
@@ -110,8 +110,8 @@ pub async fn review(options: &ReviewOptions) -> anyhow::Result<()> {
             RepoType::Svn => {
                 let mut diff_comm = Command::new("svn");
                 diff_comm.args(["diff"]);
-                if options.files.is_some() {
-                    diff_comm.args(options.files.as_ref().unwrap());
+                if let Some(files) = options.files.as_ref() {
+                    diff_comm.args(files);
                 }
                 let output = diff_comm
                     .output()
@@ -4,12 +4,14 @@ use std::{fs, io::Write};
 use anstyle::{Ansi256Color, Color, Style};
 use anyhow::Context;
 use home::home_dir;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::ProgressStyle;
 use rustix::system::uname;
 use semver::Version;
 use suppaftp::FtpStream;
 use tempfile::NamedTempFile;
 
+use crate::utils::progress_bar;
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 struct ReleaseInfo {
     version: String,
@@ -42,7 +44,7 @@ pub(crate) fn update(version: Option<String>) -> anyhow::Result<()> {
             v
         } else {
             // Checking for the latest release
-            let pbar = ProgressBar::no_length()
+            let pbar = progress_bar::no_length()
                 .with_style(ProgressStyle::with_template("Checking for update...")?);
             pbar.tick();
             let data = ftp_stream
@@ -70,7 +72,7 @@ pub(crate) fn update(version: Option<String>) -> anyhow::Result<()> {
         .as_str(),
     )?;
 
-    let pbar = ProgressBar::no_length().with_style(ProgressStyle::with_template(
+    let pbar = progress_bar::no_length().with_style(ProgressStyle::with_template(
         format!("Updating rua to {}...", target_version).as_str(),
     )?);
     pbar.tick();
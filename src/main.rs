@@ -13,6 +13,11 @@ fn main() -> anyhow::Result<()> {
         libc::signal(libc::SIGPIPE, libc::SIG_DFL);
     }
 
+    // Acting as a compiler shim for the wrapper engine of compdb
+    if core::compdb::is_compiler_shim() {
+        return core::compdb::run_compiler_shim();
+    }
+
     let args = app::Cli::parse();
 
     app::run_app(&args)
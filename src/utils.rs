@@ -11,10 +11,138 @@ use quick_xml::reader::Reader;
 use regex::Regex;
 
 pub(crate) mod progress_bar {
-    use std::time::Duration;
+    use std::io::{self, IsTerminal, Write};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use indicatif::{ProgressBar, ProgressDrawTarget, TermLike};
 
     pub(crate) const TICK_INTERVAL: Duration = Duration::from_millis(120);
     pub(crate) const TICK_CHARS: &str = "⣧⣶⣼⣹⢻⠿⡟⣏";
+    /// How often a spinning bar is logged when stderr is not a terminal
+    const PLAIN_LOG_INTERVAL: Duration = Duration::from_secs(10);
+    const PLAIN_LOG_WIDTH: u16 = 512;
+
+    /// A progress bar without length, drawn as plain log lines when stderr is not a terminal
+    pub(crate) fn no_length() -> ProgressBar {
+        if io::stderr().is_terminal() {
+            ProgressBar::no_length()
+        } else {
+            ProgressBar::with_draw_target(
+                None,
+                ProgressDrawTarget::term_like(Box::new(PlainLog::default())),
+            )
+        }
+    }
+
+    /// Writes each distinct frame of a bar as a line. Frames with a spinner are in progress,
+    /// of which only the first one and then one every `PLAIN_LOG_INTERVAL` are written.
+    #[derive(Debug, Default)]
+    struct PlainLog {
+        frame: Mutex<String>,
+        last: Mutex<(String, Option<Instant>)>,
+    }
+
+    impl TermLike for PlainLog {
+        fn width(&self) -> u16 {
+            PLAIN_LOG_WIDTH
+        }
+
+        fn move_cursor_up(&self, _n: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_down(&self, _n: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_right(&self, _n: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_left(&self, _n: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write_line(&self, s: &str) -> io::Result<()> {
+            let mut frame = self.frame.lock().unwrap();
+            frame.push_str(s);
+            frame.push('\n');
+            Ok(())
+        }
+
+        fn write_str(&self, s: &str) -> io::Result<()> {
+            self.frame.lock().unwrap().push_str(s);
+            Ok(())
+        }
+
+        fn clear_line(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            let frame = std::mem::take(&mut *self.frame.lock().unwrap());
+            let spinning = frame.chars().any(|c| TICK_CHARS.contains(c));
+            let text = frame
+                .lines()
+                .map(|line| {
+                    let mut plain = String::with_capacity(line.len());
+                    let mut chars = line.chars().peekable();
+                    while let Some(c) = chars.next() {
+                        if TICK_CHARS.contains(c) {
+                            // Drop the spinner along with the space separating it
+                            chars.next_if_eq(&' ');
+                        } else if c != '\r' {
+                            plain.push(c);
+                        }
+                    }
+                    plain.trim_end().to_string()
+                })
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n");
+            let mut last = self.last.lock().unwrap();
+            if text.is_empty() || text == last.0 {
+                return Ok(());
+            }
+            if spinning {
+                if last.1.is_some_and(|x| x.elapsed() < PLAIN_LOG_INTERVAL) {
+                    return Ok(());
+                }
+                last.1 = Some(Instant::now());
+            } else {
+                last.1 = None;
+            }
+            writeln!(io::stderr(), "{}", text)?;
+            last.0 = text;
+            Ok(())
+        }
+    }
+}
+
+/// Status lines which are completed in place by a carriage return, or written as two plain
+/// lines when stderr is not a terminal
+pub(crate) mod status_line {
+    use std::fmt::Display;
+    use std::io::{self, IsTerminal, Write};
+
+    pub(crate) fn begin(text: impl Display) -> io::Result<()> {
+        let mut stderr = io::stderr();
+        if stderr.is_terminal() {
+            write!(stderr, "{}", text)?;
+            stderr.flush()
+        } else {
+            writeln!(stderr, "{}", text)
+        }
+    }
+
+    pub(crate) fn end(text: impl Display) {
+        if io::stderr().is_terminal() {
+            eprintln!("\r{}", text);
+        } else {
+            eprintln!("{}", text);
+        }
+    }
 }
 
 #[allow(unused)]
@@ -152,6 +280,20 @@ impl RepoInfo {
     pub fn branch(&self) -> &str {
         &self.branch
     }
+
+    /// Distance from the current commit to the given revision. It is the number of commits
+    /// between them through their merge base for git, and the difference of the revision numbers
+    /// for svn. Returns None if the revision is unknown.
+    pub fn distance_to(&self, revision: &str) -> Option<u64> {
+        match self.repo_type {
+            RepoType::Git => GitInfo::distance(&self.work_dir, revision).ok(),
+            RepoType::Svn => {
+                let current: i64 = self.commit_id.parse().ok()?;
+                let other: i64 = revision.parse().ok()?;
+                Some(current.abs_diff(other))
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -211,11 +353,28 @@ impl GitInfo {
             commit_id: short_id.to_string(),
             committer: committer.name.to_string(),
             commit_time: commit_time.to_string(),
-            branch: branch,
+            branch,
             origin_push_url,
         })
     }
 
+    /// Number of commits from HEAD and the given revision to their merge base
+    pub(self) fn distance(work_dir: &str, revision: &str) -> anyhow::Result<u64> {
+        let repo = gix::open(work_dir)?;
+        let head = repo.head_id().context("Can not find HEAD")?.detach();
+        let other = repo
+            .rev_parse_single(revision)?
+            .object()?
+            .peel_to_commit()?
+            .id;
+        let base = repo.merge_base(head, other)?.detach();
+        let mut distance = 0;
+        for tip in [head, other] {
+            distance += repo.rev_walk([tip]).with_hidden([base]).all()?.count() as u64;
+        }
+        Ok(distance)
+    }
+
     pub(self) fn repo_url(&self) -> &str {
         &self.origin_push_url
     }
@@ -363,10 +522,8 @@ impl SvnInfo {
                         _ => {}
                     }
                 }
-                Ok(Event::End(elem)) => {
-                    if level.ends_with(elem.name().as_ref()) {
-                        level.truncate(level.len() - elem.name().as_ref().len() - 1);
-                    }
+                Ok(Event::End(elem)) if level.ends_with(elem.name().as_ref()) => {
+                    level.truncate(level.len() - elem.name().as_ref().len() - 1);
                 }
                 Ok(Event::Text(elem)) => {
                     let s = elem.decode()?.trim().to_owned();
@@ -515,3 +672,237 @@ pub(crate) fn normalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
     }
     normalized
 }
+
+/// Quote an argument so that it survives a round trip through the shell. Arguments made up of
+/// safe characters only are returned as they are.
+pub(crate) fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-+=/.,:@%^".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Split a command line into words the way a POSIX shell does, honoring single quotes, double
+/// quotes and backslash escapes. Expansions are not performed.
+pub(crate) fn shell_split(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(x) => word.push(x),
+                        None => bail!("Unterminated single quote in: {}", line),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(x @ ('"' | '\\' | '$' | '`')) => word.push(x),
+                            Some('\n') => {}
+                            Some(x) => {
+                                word.push('\\');
+                                word.push(x);
+                            }
+                            None => bail!("Unterminated double quote in: {}", line),
+                        },
+                        Some(x) => word.push(x),
+                        None => bail!("Unterminated double quote in: {}", line),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                // A line continuation is removed, starting no word
+                Some('\n') => {}
+                Some(x) => {
+                    in_word = true;
+                    word.push(x);
+                }
+                None => {
+                    in_word = true;
+                    word.push('\\');
+                }
+            },
+            x if x.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            x => {
+                in_word = true;
+                word.push(x);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Format a byte count with binary units, such as `12.3 MiB`.
+pub(crate) fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = n as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", n, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Runner used when none is configured, which runs the command in the build container with the
+/// output redirected there
+pub(crate) const DEFAULT_RUNNER: &str = "hsdocker7 {logged_command}";
+
+/// How build commands are run, such as in the build container by hsdocker7
+#[derive(Clone, Debug)]
+pub(crate) enum Runner {
+    /// Run by the shell directly, with the output going to the log file
+    Direct,
+    /// Run by a command line template, in which `{command}`, `{cwd}` and `{log}` are replaced
+    /// with the shell-quoted command, working directory and log file, and `{logged_command}`
+    /// with the command redirecting its output to the log file, quoted as a whole
+    Template(String),
+}
+
+impl Runner {
+    /// Pick the runner configured for the command, falling back to the global one and then the
+    /// default. Commands are always run directly when already inside a container.
+    pub(crate) fn new(global: Option<&str>, specific: Option<&str>) -> Runner {
+        if Path::new("/.dockerenv").exists() || Path::new("/run/.containerenv").exists() {
+            return Runner::Direct;
+        }
+        match specific.or(global).unwrap_or(DEFAULT_RUNNER) {
+            "direct" => Runner::Direct,
+            template => Runner::Template(template.to_string()),
+        }
+    }
+
+    fn expand(template: &str, command: &str, cwd: &Path, log: &Path) -> String {
+        let logged_command = format!("{} >{} 2>&1", command, shell_quote(&log.to_string_lossy()));
+        template
+            .replace("{logged_command}", &shell_quote(&logged_command))
+            .replace("{command}", &shell_quote(command))
+            .replace("{cwd}", &shell_quote(&cwd.to_string_lossy()))
+            .replace("{log}", &shell_quote(&log.to_string_lossy()))
+    }
+
+    /// Process running the shell command under the directory, with the output going to the
+    /// log file. A relative log file is relative to the directory.
+    pub(crate) fn command(&self, command: &str, cwd: &Path, log: &Path) -> anyhow::Result<Command> {
+        let mut process = Command::new("sh");
+        process.current_dir(cwd);
+        match self {
+            Runner::Direct => {
+                let file = std::fs::File::create(cwd.join(log))
+                    .context(format!("Failed to create {}", log.display()))?;
+                process
+                    .arg("-c")
+                    .arg(command)
+                    .stdout(file.try_clone()?)
+                    .stderr(file);
+            }
+            Runner::Template(template) => {
+                process
+                    .arg("-c")
+                    .arg(Runner::expand(template, command, cwd, log));
+            }
+        }
+        Ok(process)
+    }
+
+    /// Command line equivalent to [`Runner::command`] for users to run by hand
+    pub(crate) fn command_line(&self, command: &str, cwd: &Path, log: &Path) -> String {
+        match self {
+            Runner::Direct => {
+                let line = format!("{} >{} 2>&1", command, shell_quote(&log.to_string_lossy()));
+                if cwd == Path::new(".") {
+                    line
+                } else {
+                    format!("cd {} && {}", shell_quote(&cwd.to_string_lossy()), line)
+                }
+            }
+            Runner::Template(template) => Runner::expand(template, command, cwd, log),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_split_quotes_and_escapes() {
+        let words = shell_split(r#"gcc -DSTR="\"x y\"" -I'my dir' a\ b.c "$HOME" -c"#).unwrap();
+        assert_eq!(
+            words,
+            ["gcc", r#"-DSTR="x y""#, "-Imy dir", "a b.c", "$HOME", "-c"]
+        );
+    }
+
+    #[test]
+    fn shell_split_whitespace_and_continuations() {
+        assert_eq!(shell_split("  a\t b \\\n c\n").unwrap(), ["a", "b", "c"]);
+        assert_eq!(shell_split(r#"'' "" x"#).unwrap(), ["", "", "x"]);
+        assert_eq!(shell_split(r#""a\nb""#).unwrap(), [r"a\nb"]);
+        assert!(shell_split("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn shell_split_unterminated_quotes() {
+        assert!(shell_split("gcc 'abc").is_err());
+        assert!(shell_split(r#"gcc "abc"#).is_err());
+    }
+
+    #[test]
+    fn shell_quote_round_trips() {
+        let args = ["gcc", "-DX=1", "", "a b", "it's", r#"-DS="v""#, "$x"];
+        let line = args
+            .iter()
+            .map(|x| shell_quote(x))
+            .collect::<Vec<String>>()
+            .join(" ");
+        assert_eq!(shell_split(&line).unwrap(), args);
+        assert_eq!(shell_quote("-I/work/include"), "-I/work/include");
+    }
+
+    #[test]
+    fn default_runner_redirects_in_container() {
+        let runner = Runner::Template(DEFAULT_RUNNER.to_string());
+        assert_eq!(
+            runner.command_line("make -C src a-dnv", Path::new("."), Path::new("build.log")),
+            "hsdocker7 'make -C src a-dnv >build.log 2>&1'"
+        );
+    }
+
+    #[test]
+    fn runner_template_placeholders() {
+        let runner = Runner::Template("run -w {cwd} sh -c {command} >{log} 2>&1".to_string());
+        assert_eq!(
+            runner.command_line("make x", Path::new("/my dir"), Path::new("a.log")),
+            "run -w '/my dir' sh -c 'make x' >a.log 2>&1"
+        );
+        assert_eq!(
+            Runner::Direct.command_line("make x", Path::new("src"), Path::new("a.log")),
+            "cd src && make x >a.log 2>&1"
+        );
+    }
+}